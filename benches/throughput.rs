@@ -0,0 +1,171 @@
+use std::process::ExitCode;
+use std::time::Instant;
+
+use bitvec::prelude::*;
+
+use learn::huffman_compress;
+use learn::parallel;
+
+// Throughput of the encoder on generated data. Run it with
+// `cargo bench --bench throughput -- [megabytes]`.
+
+const USAGE: &str = "usage: cargo bench --bench throughput -- [megabytes]";
+
+fn main() -> ExitCode {
+    // cargo bench passes --bench along
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    let megabytes = match args.as_slice() {
+        [] => 16,
+        [n] => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(megabytes) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// the timings mean nothing if the two ways of doing it disagree
+fn agree<T: PartialEq>(a: &T, b: &T, what: &str) -> Result<(), String> {
+    if a == b {
+        Ok(())
+    } else {
+        Err(format!("{} do not agree", what))
+    }
+}
+
+// Text-like bytes: a skewed alphabet so the code lengths spread from a few
+// bits to a dozen or so.
+fn generate_data(len: usize) -> Vec<u8> {
+    const ALPHABET: &[u8] = b"eeeeeeeetttttaaaaoooiiinnnsshhrrdlcumwfgypbvkjxqz    \n,.";
+
+    let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ALPHABET[(state % ALPHABET.len() as u64) as usize]
+        })
+        .collect()
+}
+
+fn report(name: &str, bytes: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{:<28} {:>8.1} ms {:>10.1} MB/s",
+        name,
+        secs * 1000.0,
+        bytes as f64 / secs / 1_000_000.0
+    );
+}
+
+fn run(megabytes: usize) -> Result<(), String> {
+    let data = generate_data(megabytes * 1_000_000);
+    println!("input: {} bytes of generated text", data.len());
+
+    // what `count_frequency` used to do
+    let start = Instant::now();
+    let mut single = vec![0u64; 256];
+    for &ch in &data {
+        single[ch as usize] += 1;
+    }
+    report("count frequency, one table", data.len(), start);
+
+    let start = Instant::now();
+    let frequency = huffman_compress::count_frequency(&data);
+    report("count frequency", data.len(), start);
+    agree(&frequency, &single, "the frequency counts")?;
+
+    let start = Instant::now();
+    let parallel = parallel::count_frequency_parallel(&data, parallel::default_workers());
+    report("count frequency, threads", data.len(), start);
+    agree(&parallel, &single, "the threaded frequency counts")?;
+
+    // one long run, where a single table stalls on every increment
+    let runs = vec![b'k'; data.len()];
+    let start = Instant::now();
+    let mut single = vec![0u64; 256];
+    for &ch in &runs {
+        single[ch as usize] += 1;
+    }
+    report("count runs, one table", runs.len(), start);
+    let start = Instant::now();
+    let counted = huffman_compress::count_frequency(&runs);
+    report("count runs", runs.len(), start);
+    agree(&counted, &single, "the run counts")?;
+
+    let start = Instant::now();
+    let heap_lengths = huffman_compress::code_lengths_from_dic(
+        &huffman_compress::generate_haffman_dic_from_frequency(&frequency),
+    );
+    println!(
+        "code lengths, heap: {:.3} ms",
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+
+    let start = Instant::now();
+    let lengths = huffman_compress::build_code_lengths_linear(&frequency);
+    println!(
+        "code lengths, in place: {:.3} ms",
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+    agree(&lengths, &heap_lengths, "the code length builders")?;
+
+    let start = Instant::now();
+    let lengths = huffman_compress::build_code_lengths(&frequency);
+    let dic = huffman_compress::generate_canonical_dic(&lengths);
+    let table = huffman_compress::packed_table_from_lengths(&lengths);
+    println!(
+        "build tables: {:.3} ms",
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+
+    // what `generate_new_content` used to do
+    let start = Instant::now();
+    let mut bits: BitVec<u8, Msb0> = BitVec::new();
+    for ch in &data {
+        bits.extend(dic[ch].iter());
+    }
+    report("encode, BitVec per bit", data.len(), start);
+
+    let start = Instant::now();
+    let packed = huffman_compress::encode_payload(&data, &table);
+    report("encode, packed u32 codes", data.len(), start);
+
+    agree(&packed, &bits.into_vec(), "the encoders")?;
+
+    let start = Instant::now();
+    let compressed = huffman_compress::compress(&data);
+    report("compress", data.len(), start);
+
+    let start = Instant::now();
+    let decompressed = huffman_compress::decompress(&compressed).map_err(|e| e.to_string())?;
+    report("decompress", data.len(), start);
+
+    agree(&decompressed, &data, "the input and the round trip")?;
+    println!(
+        "ratio: {} -> {} bytes ({:.1}%)",
+        data.len(),
+        compressed.len(),
+        compressed.len() as f64 * 100.0 / data.len() as f64
+    );
+
+    Ok(())
+}
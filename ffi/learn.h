@@ -0,0 +1,88 @@
+/*
+ * C interface to the learn huffman coding library, see ffi/src/lib.rs.
+ *
+ * Link against liblearn_ffi.a (plus -lpthread -ldl -lm) or liblearn_ffi.so,
+ * both built by `cargo build -p learn-ffi`. Every function returning int
+ * returns LEARN_OK or one of the LEARN_ERROR_* codes; none of them aborts or
+ * unwinds.
+ *
+ * Pointers may be NULL only where the matching length is 0. Buffers are only
+ * used for the duration of the call. Contexts must not be used from two
+ * threads at once.
+ */
+#ifndef LEARN_H
+#define LEARN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LEARN_OK (0)
+/* a NULL pointer, a level out of range, or a call out of order */
+#define LEARN_ERROR_INVALID_ARGUMENT (-1)
+/* *dst_len has been set to the size needed */
+#define LEARN_ERROR_BUFFER_TOO_SMALL (-2)
+#define LEARN_ERROR_CORRUPT (-3)
+#define LEARN_ERROR_CHECKSUM (-4)
+#define LEARN_ERROR_IO (-5)
+#define LEARN_ERROR_INTERNAL (-6)
+
+#define LEARN_MIN_LEVEL 1
+#define LEARN_DEFAULT_LEVEL 6
+#define LEARN_MAX_LEVEL 9
+
+/* static, never to be freed */
+const char *learn_status_str(int status);
+
+/* --- whole buffers --- */
+
+/* the most learn_compress can write for src_len bytes, at any level */
+size_t learn_compress_bound(size_t src_len);
+
+/*
+ * Compresses src into dst and sets *dst_len to the compressed size. With too
+ * small a dst it returns LEARN_ERROR_BUFFER_TOO_SMALL and *dst_len is the size
+ * that would have been needed.
+ */
+int learn_compress(const uint8_t *src, size_t src_len, uint8_t *dst, size_t dst_cap,
+                   size_t *dst_len, int level);
+
+/* reads anything learn_compress or a learn_encoder wrote; *dst_len as above */
+int learn_decompress(const uint8_t *src, size_t src_len, uint8_t *dst, size_t dst_cap,
+                     size_t *dst_len);
+
+/* --- streaming --- */
+
+/*
+ * Write input in pieces, read the output as it becomes available, finish,
+ * then read until a read returns 0 bytes. Writing after finishing is an
+ * error.
+ *
+ * The encoder keeps at most one block of input. The decoder needs all of its
+ * input before it can decode, so its output only appears after finishing.
+ */
+typedef struct LearnEncoder learn_encoder;
+typedef struct LearnDecoder learn_decoder;
+
+/* NULL for a level out of range */
+learn_encoder *learn_encoder_new(int level);
+int learn_encoder_write(learn_encoder *encoder, const uint8_t *src, size_t src_len);
+int learn_encoder_finish(learn_encoder *encoder);
+int learn_encoder_read(learn_encoder *encoder, uint8_t *dst, size_t dst_cap, size_t *read);
+/* NULL is ignored */
+void learn_encoder_free(learn_encoder *encoder);
+
+learn_decoder *learn_decoder_new(void);
+int learn_decoder_write(learn_decoder *decoder, const uint8_t *src, size_t src_len);
+int learn_decoder_finish(learn_decoder *decoder);
+int learn_decoder_read(learn_decoder *decoder, uint8_t *dst, size_t dst_cap, size_t *read);
+void learn_decoder_free(learn_decoder *decoder);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LEARN_H */
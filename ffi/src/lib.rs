@@ -0,0 +1,556 @@
+// The safety contract of every function here is spelled out in ffi/learn.h
+#![allow(clippy::missing_safety_doc)]
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use learn::block::{BlockWriter, DEFAULT_BLOCK_SIZE, TableMode};
+use learn::error::Error;
+use learn::huffman_compress;
+use learn::options::{self, CompressionOptions, Format, MAX_LEVEL, MIN_LEVEL};
+
+// C entry points, built into the cdylib and staticlib. Nothing unwinds into
+// C: errors come back as the status codes below and a panic is caught and
+// turned into LEARN_ERROR_INTERNAL. Keep ffi/learn.h in step with this file,
+// `test_header_declares_every_function` checks the names.
+
+pub const LEARN_OK: i32 = 0;
+// a null pointer, a level out of range, or a call out of order
+pub const LEARN_ERROR_INVALID_ARGUMENT: i32 = -1;
+// `*dst_len` says how much room is needed
+pub const LEARN_ERROR_BUFFER_TOO_SMALL: i32 = -2;
+pub const LEARN_ERROR_CORRUPT: i32 = -3;
+pub const LEARN_ERROR_CHECKSUM: i32 = -4;
+pub const LEARN_ERROR_IO: i32 = -5;
+pub const LEARN_ERROR_INTERNAL: i32 = -6;
+
+fn status(e: &Error) -> i32 {
+    match e {
+        Error::Io(_) => LEARN_ERROR_IO,
+        Error::Corrupt(_) => LEARN_ERROR_CORRUPT,
+        Error::ChecksumMismatch { .. } => LEARN_ERROR_CHECKSUM,
+        Error::BufferTooSmall { .. } => LEARN_ERROR_BUFFER_TOO_SMALL,
+        Error::Unsupported(_) => LEARN_ERROR_INVALID_ARGUMENT,
+        _ => LEARN_ERROR_INTERNAL,
+    }
+}
+
+fn guard(f: impl FnOnce() -> i32) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(LEARN_ERROR_INTERNAL)
+}
+
+// a null pointer is fine for an empty buffer
+unsafe fn input<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(unsafe { slice::from_raw_parts(data, len) }),
+    }
+}
+
+unsafe fn output<'a>(data: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&mut []),
+        (true, _) => None,
+        (false, _) => Some(unsafe { slice::from_raw_parts_mut(data, len) }),
+    }
+}
+
+fn level_options(level: i32) -> Option<CompressionOptions> {
+    let level = u8::try_from(level).ok()?;
+    (MIN_LEVEL..=MAX_LEVEL)
+        .contains(&level)
+        .then(|| CompressionOptions::new(level))
+}
+
+// copies `res` out, or says how large `dst` has to be
+unsafe fn copy_out(res: &[u8], dst: *mut u8, dst_cap: usize, dst_len: *mut usize) -> i32 {
+    unsafe { *dst_len = res.len() };
+    let Some(dst) = (unsafe { output(dst, dst_cap) }) else {
+        return LEARN_ERROR_INVALID_ARGUMENT;
+    };
+    if res.len() > dst.len() {
+        return LEARN_ERROR_BUFFER_TOO_SMALL;
+    }
+    dst[..res.len()].copy_from_slice(res);
+
+    LEARN_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn learn_status_str(status: i32) -> *const c_char {
+    let s = match status {
+        LEARN_OK => c"ok",
+        LEARN_ERROR_INVALID_ARGUMENT => c"invalid argument",
+        LEARN_ERROR_BUFFER_TOO_SMALL => c"buffer too small",
+        LEARN_ERROR_CORRUPT => c"corrupt data",
+        LEARN_ERROR_CHECKSUM => c"checksum mismatch",
+        LEARN_ERROR_IO => c"io error",
+        LEARN_ERROR_INTERNAL => c"internal error",
+        _ => c"unknown status",
+    };
+    s.as_ptr()
+}
+
+// Enough room for what any level makes of `src_len` bytes: every format falls
+// back to storing input it cannot shrink, and blocks are at least 64 KiB, so
+// the overhead is a header, a table, and a few bytes per block.
+#[unsafe(no_mangle)]
+pub extern "C" fn learn_compress_bound(src_len: usize) -> usize {
+    src_len.saturating_add(src_len / 1024).saturating_add(1024)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_compress(
+    src: *const u8,
+    src_len: usize,
+    dst: *mut u8,
+    dst_cap: usize,
+    dst_len: *mut usize,
+    level: i32,
+) -> i32 {
+    guard(|| {
+        let (Some(src), Some(options)) = (unsafe { input(src, src_len) }, level_options(level))
+        else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        if dst_len.is_null() {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        }
+
+        let res = options::compress(src, &options);
+        unsafe { copy_out(&res, dst, dst_cap, dst_len) }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_decompress(
+    src: *const u8,
+    src_len: usize,
+    dst: *mut u8,
+    dst_cap: usize,
+    dst_len: *mut usize,
+) -> i32 {
+    guard(|| {
+        let Some(src) = (unsafe { input(src, src_len) }) else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        if dst_len.is_null() {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        }
+
+        // the single container decodes straight into `dst`
+        if Format::detect(src) == Some(Format::Single) {
+            let Some(dst) = (unsafe { output(dst, dst_cap) }) else {
+                return LEARN_ERROR_INVALID_ARGUMENT;
+            };
+            return match huffman_compress::decode_into(src, dst) {
+                Ok(len) => {
+                    unsafe { *dst_len = len };
+                    LEARN_OK
+                }
+                Err(e) => {
+                    if let Error::BufferTooSmall { needed } = e {
+                        unsafe { *dst_len = needed };
+                    }
+                    status(&e)
+                }
+            };
+        }
+
+        match options::decompress(src) {
+            Ok(res) => unsafe { copy_out(&res, dst, dst_cap, dst_len) },
+            Err(e) => status(&e),
+        }
+    })
+}
+
+// Output a context has produced but the caller has not read yet
+unsafe fn drain(pending: &mut VecDeque<u8>, dst: *mut u8, dst_cap: usize, read: *mut usize) -> i32 {
+    let Some(dst) = (unsafe { output(dst, dst_cap) }) else {
+        return LEARN_ERROR_INVALID_ARGUMENT;
+    };
+    if read.is_null() {
+        return LEARN_ERROR_INVALID_ARGUMENT;
+    }
+
+    let len = dst.len().min(pending.len());
+    for (d, s) in dst.iter_mut().zip(pending.drain(..len)) {
+        *d = s;
+    }
+    unsafe { *read = len };
+
+    LEARN_OK
+}
+
+// Streams a block file out while the input comes in. The level's block size
+// and model are kept, but every block carries its own table, since a shared
+// one would need the whole input first.
+pub struct LearnEncoder {
+    writer: Option<BlockWriter<VecDeque<u8>>>,
+    finished: VecDeque<u8>,
+}
+
+impl LearnEncoder {
+    fn pending(&mut self) -> &mut VecDeque<u8> {
+        match &mut self.writer {
+            Some(writer) => writer.get_mut(),
+            None => &mut self.finished,
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn learn_encoder_new(level: i32) -> *mut LearnEncoder {
+    let encoder = panic::catch_unwind(|| {
+        let options = level_options(level)?;
+        let block_size = options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+        let mode = match options.context_model {
+            true => TableMode::Order1,
+            false => TableMode::PerBlock,
+        };
+        let writer = BlockWriter::new(VecDeque::new(), block_size, mode).ok()?;
+
+        Some(Box::new(LearnEncoder {
+            writer: Some(writer),
+            finished: VecDeque::new(),
+        }))
+    });
+
+    match encoder {
+        Ok(Some(encoder)) => Box::into_raw(encoder),
+        _ => ptr::null_mut(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_encoder_write(
+    encoder: *mut LearnEncoder,
+    src: *const u8,
+    src_len: usize,
+) -> i32 {
+    guard(|| {
+        let (Some(encoder), Some(src)) =
+            (unsafe { encoder.as_mut() }, unsafe { input(src, src_len) })
+        else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        let Some(writer) = encoder.writer.as_mut() else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+
+        match writer.write_all(src) {
+            Ok(()) => LEARN_OK,
+            Err(e) => status(&e.into()),
+        }
+    })
+}
+
+// No more input; the rest of the output can be read afterwards
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_encoder_finish(encoder: *mut LearnEncoder) -> i32 {
+    guard(|| {
+        let Some(encoder) = (unsafe { encoder.as_mut() }) else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        let Some(writer) = encoder.writer.take() else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+
+        match writer.finish() {
+            Ok(finished) => {
+                encoder.finished = finished;
+                LEARN_OK
+            }
+            Err(e) => status(&e),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_encoder_read(
+    encoder: *mut LearnEncoder,
+    dst: *mut u8,
+    dst_cap: usize,
+    read: *mut usize,
+) -> i32 {
+    guard(|| {
+        let Some(encoder) = (unsafe { encoder.as_mut() }) else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        unsafe { drain(encoder.pending(), dst, dst_cap, read) }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_encoder_free(encoder: *mut LearnEncoder) {
+    if !encoder.is_null() {
+        drop(unsafe { Box::from_raw(encoder) });
+    }
+}
+
+// Takes compressed input in pieces. The formats keep their index or lengths
+// where only the whole input has them, so it is decoded once finished.
+pub struct LearnDecoder {
+    input: Option<Vec<u8>>,
+    output: VecDeque<u8>,
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn learn_decoder_new() -> *mut LearnDecoder {
+    Box::into_raw(Box::new(LearnDecoder {
+        input: Some(Vec::new()),
+        output: VecDeque::new(),
+    }))
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_decoder_write(
+    decoder: *mut LearnDecoder,
+    src: *const u8,
+    src_len: usize,
+) -> i32 {
+    guard(|| {
+        let (Some(decoder), Some(src)) =
+            (unsafe { decoder.as_mut() }, unsafe { input(src, src_len) })
+        else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        let Some(input) = decoder.input.as_mut() else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+
+        input.extend_from_slice(src);
+        LEARN_OK
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_decoder_finish(decoder: *mut LearnDecoder) -> i32 {
+    guard(|| {
+        let Some(decoder) = (unsafe { decoder.as_mut() }) else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        let Some(input) = decoder.input.take() else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+
+        match options::decompress(&input) {
+            Ok(res) => {
+                decoder.output = res.into();
+                LEARN_OK
+            }
+            Err(e) => status(&e),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_decoder_read(
+    decoder: *mut LearnDecoder,
+    dst: *mut u8,
+    dst_cap: usize,
+    read: *mut usize,
+) -> i32 {
+    guard(|| {
+        let Some(decoder) = (unsafe { decoder.as_mut() }) else {
+            return LEARN_ERROR_INVALID_ARGUMENT;
+        };
+        unsafe { drain(&mut decoder.output, dst, dst_cap, read) }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn learn_decoder_free(decoder: *mut LearnDecoder) {
+    if !decoder.is_null() {
+        drop(unsafe { Box::from_raw(decoder) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn sample(len: usize) -> Vec<u8> {
+        b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh".repeat(len / 32 + 1)[..len].to_vec()
+    }
+
+    #[test]
+    fn test_buffer_round_trip() {
+        let content = sample(100_000);
+        for level in [1, 6, 9] {
+            let mut compressed = vec![0u8; learn_compress_bound(content.len())];
+            let mut len = 0;
+            let status = unsafe {
+                learn_compress(
+                    content.as_ptr(),
+                    content.len(),
+                    compressed.as_mut_ptr(),
+                    compressed.len(),
+                    &mut len,
+                    level,
+                )
+            };
+            assert_eq!(status, LEARN_OK);
+            compressed.truncate(len);
+
+            // asks for exactly the room it needs
+            let mut small = vec![0u8; 10];
+            let status = unsafe {
+                learn_decompress(
+                    compressed.as_ptr(),
+                    compressed.len(),
+                    small.as_mut_ptr(),
+                    small.len(),
+                    &mut len,
+                )
+            };
+            assert_eq!(status, LEARN_ERROR_BUFFER_TOO_SMALL);
+            assert_eq!(len, content.len());
+
+            let mut decompressed = vec![0u8; len];
+            let status = unsafe {
+                learn_decompress(
+                    compressed.as_ptr(),
+                    compressed.len(),
+                    decompressed.as_mut_ptr(),
+                    decompressed.len(),
+                    &mut len,
+                )
+            };
+            assert_eq!(status, LEARN_OK);
+            assert_eq!(decompressed, content);
+        }
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        let mut len = 0;
+        let status = unsafe { learn_compress(ptr::null(), 5, ptr::null_mut(), 0, &mut len, 6) };
+        assert_eq!(status, LEARN_ERROR_INVALID_ARGUMENT);
+        let status = unsafe { learn_compress(ptr::null(), 0, ptr::null_mut(), 0, &mut len, 10) };
+        assert_eq!(status, LEARN_ERROR_INVALID_ARGUMENT);
+        assert!(learn_encoder_new(0).is_null());
+
+        let garbage = b"HFC1 not really";
+        let status = unsafe {
+            learn_decompress(
+                garbage.as_ptr(),
+                garbage.len(),
+                ptr::null_mut(),
+                0,
+                &mut len,
+            )
+        };
+        assert_eq!(status, LEARN_ERROR_CORRUPT);
+
+        let encoder = learn_encoder_new(6);
+        unsafe {
+            assert_eq!(learn_encoder_finish(encoder), LEARN_OK);
+            assert_eq!(
+                learn_encoder_write(encoder, b"late".as_ptr(), 4),
+                LEARN_ERROR_INVALID_ARGUMENT
+            );
+            learn_encoder_free(encoder);
+        }
+    }
+
+    #[test]
+    fn test_streaming_round_trip() {
+        let content = sample(300_000);
+        for level in [3, 7] {
+            let encoder = learn_encoder_new(level);
+            let mut compressed: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 4096];
+            let mut read = 0;
+            unsafe {
+                for piece in content.chunks(10_000) {
+                    assert_eq!(
+                        learn_encoder_write(encoder, piece.as_ptr(), piece.len()),
+                        LEARN_OK
+                    );
+                    assert_eq!(
+                        learn_encoder_read(encoder, buf.as_mut_ptr(), buf.len(), &mut read),
+                        LEARN_OK
+                    );
+                    compressed.extend_from_slice(&buf[..read]);
+                }
+                assert_eq!(learn_encoder_finish(encoder), LEARN_OK);
+                loop {
+                    assert_eq!(
+                        learn_encoder_read(encoder, buf.as_mut_ptr(), buf.len(), &mut read),
+                        LEARN_OK
+                    );
+                    if read == 0 {
+                        break;
+                    }
+                    compressed.extend_from_slice(&buf[..read]);
+                }
+                learn_encoder_free(encoder);
+            }
+
+            let decoder = learn_decoder_new();
+            let mut decompressed: Vec<u8> = Vec::new();
+            unsafe {
+                for piece in compressed.chunks(777) {
+                    assert_eq!(
+                        learn_decoder_write(decoder, piece.as_ptr(), piece.len()),
+                        LEARN_OK
+                    );
+                }
+                assert_eq!(learn_decoder_finish(decoder), LEARN_OK);
+                loop {
+                    assert_eq!(
+                        learn_decoder_read(decoder, buf.as_mut_ptr(), buf.len(), &mut read),
+                        LEARN_OK
+                    );
+                    if read == 0 {
+                        break;
+                    }
+                    decompressed.extend_from_slice(&buf[..read]);
+                }
+                learn_decoder_free(decoder);
+            }
+            assert_eq!(decompressed, content);
+        }
+    }
+
+    #[test]
+    fn test_header_declares_every_function() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let header = std::fs::read_to_string(root.join("learn.h")).unwrap();
+        let source = std::fs::read_to_string(root.join("src/lib.rs")).unwrap();
+
+        let mut functions = 0;
+        for line in source.lines() {
+            let Some((_, rest)) = line.split_once("extern \"C\" fn ") else {
+                continue;
+            };
+            let name = rest.split('(').next().unwrap();
+            assert!(
+                header.contains(&format!("{}(", name)),
+                "{} is not in learn.h",
+                name
+            );
+            functions += 1;
+        }
+        assert_eq!(functions, 14);
+
+        for (name, value) in [
+            ("LEARN_OK", LEARN_OK),
+            ("LEARN_ERROR_INVALID_ARGUMENT", LEARN_ERROR_INVALID_ARGUMENT),
+            ("LEARN_ERROR_BUFFER_TOO_SMALL", LEARN_ERROR_BUFFER_TOO_SMALL),
+            ("LEARN_ERROR_CORRUPT", LEARN_ERROR_CORRUPT),
+            ("LEARN_ERROR_CHECKSUM", LEARN_ERROR_CHECKSUM),
+            ("LEARN_ERROR_IO", LEARN_ERROR_IO),
+            ("LEARN_ERROR_INTERNAL", LEARN_ERROR_INTERNAL),
+        ] {
+            assert!(header.contains(&format!("#define {} ({})", name, value)));
+        }
+    }
+}
@@ -0,0 +1,158 @@
+/* Built and run by the test_c_program test in ffi/tests/c_program.rs */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "learn.h"
+
+#define CHECK(cond)                                                            \
+    do {                                                                       \
+        if (!(cond)) {                                                         \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__,   \
+                    #cond);                                                    \
+            exit(1);                                                           \
+        }                                                                      \
+    } while (0)
+
+static uint8_t *sample(size_t len) {
+    static const char text[] = "the quick brown fox jumps over the lazy dog\n";
+    uint8_t *res = malloc(len);
+    CHECK(res != NULL);
+    for (size_t i = 0; i < len; i++) {
+        res[i] = (uint8_t)text[i % (sizeof(text) - 1)];
+    }
+    return res;
+}
+
+static void test_buffers(const uint8_t *content, size_t len) {
+    for (int level = LEARN_MIN_LEVEL; level <= LEARN_MAX_LEVEL; level++) {
+        size_t cap = learn_compress_bound(len);
+        uint8_t *compressed = malloc(cap);
+        size_t compressed_len = 0;
+        CHECK(learn_compress(content, len, compressed, cap, &compressed_len, level) ==
+              LEARN_OK);
+        CHECK(compressed_len < len);
+
+        size_t needed = 0;
+        CHECK(learn_decompress(compressed, compressed_len, NULL, 0, &needed) ==
+              LEARN_ERROR_BUFFER_TOO_SMALL);
+        CHECK(needed == len);
+
+        uint8_t *decompressed = malloc(needed);
+        size_t decompressed_len = 0;
+        CHECK(learn_decompress(compressed, compressed_len, decompressed, needed,
+                               &decompressed_len) == LEARN_OK);
+        CHECK(decompressed_len == len);
+        CHECK(memcmp(decompressed, content, len) == 0);
+
+        /* damage the stored checksum of the default container */
+        if (level == LEARN_DEFAULT_LEVEL) {
+            compressed[13] ^= 0xFF;
+            CHECK(learn_decompress(compressed, compressed_len, decompressed, needed,
+                                   &decompressed_len) == LEARN_ERROR_CHECKSUM);
+        }
+
+        free(compressed);
+        free(decompressed);
+    }
+}
+
+static void test_incompressible(void) {
+    size_t len = 200000;
+    uint8_t *noise = malloc(len);
+    uint32_t state = 1;
+    for (size_t i = 0; i < len; i++) {
+        state = state * 1103515245 + 12345;
+        noise[i] = (uint8_t)(state >> 16);
+    }
+
+    size_t cap = learn_compress_bound(len);
+    uint8_t *compressed = malloc(cap);
+    for (int level = LEARN_MIN_LEVEL; level <= LEARN_MAX_LEVEL; level++) {
+        size_t compressed_len = 0;
+        CHECK(learn_compress(noise, len, compressed, cap, &compressed_len, level) ==
+              LEARN_OK);
+        CHECK(compressed_len <= cap);
+    }
+
+    free(noise);
+    free(compressed);
+}
+
+static void test_streaming(const uint8_t *content, size_t len) {
+    uint8_t buf[1000];
+    size_t read = 0;
+
+    learn_encoder *encoder = learn_encoder_new(LEARN_DEFAULT_LEVEL);
+    CHECK(encoder != NULL);
+    size_t cap = learn_compress_bound(len);
+    uint8_t *compressed = malloc(cap);
+    size_t compressed_len = 0;
+    for (size_t pos = 0; pos < len; pos += 4096) {
+        size_t piece = len - pos < 4096 ? len - pos : 4096;
+        CHECK(learn_encoder_write(encoder, content + pos, piece) == LEARN_OK);
+        do {
+            CHECK(learn_encoder_read(encoder, buf, sizeof(buf), &read) == LEARN_OK);
+            memcpy(compressed + compressed_len, buf, read);
+            compressed_len += read;
+        } while (read > 0);
+    }
+    CHECK(learn_encoder_finish(encoder) == LEARN_OK);
+    CHECK(learn_encoder_finish(encoder) == LEARN_ERROR_INVALID_ARGUMENT);
+    do {
+        CHECK(learn_encoder_read(encoder, buf, sizeof(buf), &read) == LEARN_OK);
+        memcpy(compressed + compressed_len, buf, read);
+        compressed_len += read;
+    } while (read > 0);
+    learn_encoder_free(encoder);
+
+    learn_decoder *decoder = learn_decoder_new();
+    CHECK(decoder != NULL);
+    for (size_t pos = 0; pos < compressed_len; pos += 333) {
+        size_t piece = compressed_len - pos < 333 ? compressed_len - pos : 333;
+        CHECK(learn_decoder_write(decoder, compressed + pos, piece) == LEARN_OK);
+    }
+    CHECK(learn_decoder_finish(decoder) == LEARN_OK);
+    size_t decompressed_len = 0;
+    do {
+        CHECK(learn_decoder_read(decoder, buf, sizeof(buf), &read) == LEARN_OK);
+        CHECK(decompressed_len + read <= len);
+        CHECK(memcmp(buf, content + decompressed_len, read) == 0);
+        decompressed_len += read;
+    } while (read > 0);
+    CHECK(decompressed_len == len);
+    learn_decoder_free(decoder);
+
+    free(compressed);
+}
+
+static void test_errors(void) {
+    size_t len = 0;
+    CHECK(learn_compress(NULL, 0, NULL, 0, &len, 0) == LEARN_ERROR_INVALID_ARGUMENT);
+    CHECK(learn_compress(NULL, 10, NULL, 0, &len, 6) == LEARN_ERROR_INVALID_ARGUMENT);
+    CHECK(learn_encoder_new(LEARN_MAX_LEVEL + 1) == NULL);
+    CHECK(learn_encoder_write(NULL, NULL, 0) == LEARN_ERROR_INVALID_ARGUMENT);
+    learn_encoder_free(NULL);
+
+    const uint8_t garbage[] = "definitely not compressed";
+    CHECK(learn_decompress(garbage, sizeof(garbage), NULL, 0, &len) == LEARN_ERROR_CORRUPT);
+    CHECK(strcmp(learn_status_str(LEARN_ERROR_CORRUPT), "corrupt data") == 0);
+
+    learn_decoder *decoder = learn_decoder_new();
+    CHECK(learn_decoder_write(decoder, garbage, sizeof(garbage)) == LEARN_OK);
+    CHECK(learn_decoder_finish(decoder) == LEARN_ERROR_CORRUPT);
+    learn_decoder_free(decoder);
+}
+
+int main(void) {
+    size_t len = 300000;
+    uint8_t *content = sample(len);
+
+    test_buffers(content, len);
+    test_incompressible();
+    test_streaming(content, len);
+    test_errors();
+
+    free(content);
+    return 0;
+}
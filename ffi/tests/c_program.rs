@@ -0,0 +1,38 @@
+use std::path::Path;
+use std::process::Command;
+
+// Builds the staticlib into a target directory of its own, so the cargo
+// running this test is not waited on, and links test.c against it.
+#[test]
+fn test_c_program() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = root.join("../target/ffi-test");
+    let build = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--offline", "--target-dir"])
+        .arg(&target_dir)
+        .current_dir(root)
+        .status()
+        .unwrap();
+    assert!(build.success());
+
+    let program = target_dir.join("test_ffi");
+    let compile = Command::new("cc")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(root)
+        .arg(root.join("test.c"))
+        .arg(target_dir.join("debug/liblearn_ffi.a"))
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(compile.success());
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
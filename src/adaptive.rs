@@ -0,0 +1,290 @@
+use crate::bitio::{BitOrder, BitReader, BitWriter};
+use crate::error::{Error, Result};
+use crate::huffman_compress::{self, CanonicalDecoder, PackedCodeTable, take};
+
+// Byte coding for data whose statistics wander, like a day of logs. The input
+// is looked at in windows of a fixed size, and at the start of every window
+// the encoder may swap in a table built from that window alone. Whether it
+// did is one bit in the stream, followed by the new table, so the decoder
+// switches at exactly the same points.
+//
+// magic            4 bytes, "HFW1"
+// flags            1 byte, huffman_compress::FLAG_STORED or 0
+// original length  u64, little endian
+// window           u32, bytes per window
+// then, unless stored, one bit stream, msb first, zero padded to a byte,
+// holding for every window:
+//   rebuild        1 bit, 1 when a new table follows; always 1 for the first
+//   table          symbol count (9 bits), then per symbol the symbol (8 bits)
+//                  and its code length minus one (5 bits), in canonical order
+//   codes          the window's bytes
+
+const ADAPTIVE_MAGIC: &[u8; 4] = b"HFW1";
+const ADAPTIVE_HEADER_LEN: usize = 17;
+
+pub const DEFAULT_WINDOW: usize = 64 * 1024;
+
+// When the encoder replaces the table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rebuild {
+    // at every window
+    Always,
+    // when coding the window with the current table costs more than this many
+    // parts per thousand over a table of its own, that table included
+    Drift(u32),
+}
+
+fn table_bits(lengths: &[u8]) -> u64 {
+    9 + 13 * lengths.iter().filter(|&&l| l > 0).count() as u64
+}
+
+// bits to code `frequency` with `lengths`, None when a symbol has no code
+fn cost(frequency: &[u64], lengths: &[u8]) -> Option<u64> {
+    let mut bits = 0u64;
+    for (&count, &len) in frequency.iter().zip(lengths) {
+        if count > 0 {
+            if len == 0 {
+                return None;
+            }
+            bits += count * len as u64;
+        }
+    }
+    Some(bits)
+}
+
+fn write_table(writer: &mut BitWriter<Vec<u8>>, lengths: &[u8]) {
+    let symbols: Vec<usize> = huffman_compress::canonical_ranks(lengths)
+        .into_iter()
+        .filter(|&s| lengths[s] > 0)
+        .collect();
+    writer
+        .write_bits(symbols.len() as u64, 9)
+        .expect("writing to a Vec cannot fail");
+    for s in symbols {
+        let value = ((s as u64) << 5) | (lengths[s] - 1) as u64;
+        writer
+            .write_bits(value, 13)
+            .expect("writing to a Vec cannot fail");
+    }
+}
+
+pub fn compress_adaptive(content: &[u8], window: usize, rebuild: Rebuild) -> Vec<u8> {
+    assert!(
+        window > 0 && window <= u32::MAX as usize,
+        "invalid window size"
+    );
+
+    let mut res: Vec<u8> = Vec::new();
+    res.extend_from_slice(ADAPTIVE_MAGIC);
+    res.push(0);
+    res.extend_from_slice(&(content.len() as u64).to_le_bytes());
+    res.extend_from_slice(&(window as u32).to_le_bytes());
+
+    let mut writer = BitWriter::new(res, BitOrder::MsbFirst);
+    let mut current: Option<(Vec<u8>, PackedCodeTable)> = None;
+    for chunk in content.chunks(window) {
+        let frequency = huffman_compress::count_frequency(chunk);
+        let fresh = huffman_compress::build_code_lengths(&frequency);
+
+        let keep = match (&current, rebuild) {
+            (None, _) | (_, Rebuild::Always) => false,
+            (Some((lengths, _)), Rebuild::Drift(permille)) => {
+                let fresh_bits =
+                    cost(&frequency, &fresh).expect("every symbol has a code") + table_bits(&fresh);
+                cost(&frequency, lengths)
+                    .is_some_and(|bits| bits * 1000 <= fresh_bits * (1000 + permille as u64))
+            }
+        };
+
+        writer
+            .write_bit(!keep)
+            .expect("writing to a Vec cannot fail");
+        if !keep {
+            write_table(&mut writer, &fresh);
+            let table = huffman_compress::packed_table_from_lengths(&fresh);
+            current = Some((fresh, table));
+        }
+
+        let (_, table) = current.as_ref().expect("the first window builds a table");
+        for &ch in chunk {
+            let (code, len) = table[ch as usize];
+            writer
+                .write_bits(code as u64, len as u32)
+                .expect("writing to a Vec cannot fail");
+        }
+    }
+    let mut res = writer.finish().expect("writing to a Vec cannot fail");
+
+    // the tables have to pay for themselves
+    if res.len() >= ADAPTIVE_HEADER_LEN + content.len() {
+        res.truncate(ADAPTIVE_HEADER_LEN);
+        res[4] = huffman_compress::FLAG_STORED;
+        res.extend_from_slice(content);
+    }
+
+    res
+}
+
+// reading a slice only fails at its end
+fn read_bits(reader: &mut BitReader<&[u8]>, n: u32) -> Result<u32> {
+    match reader.read_bits(n) {
+        Ok(value) => Ok(value as u32),
+        Err(_) => Err(Error::Corrupt("adaptive payload is truncated")),
+    }
+}
+
+fn read_table(reader: &mut BitReader<&[u8]>) -> Result<Vec<u8>> {
+    let symbol_count = read_bits(reader, 9)?;
+    if symbol_count == 0 || symbol_count > 256 {
+        return Err(Error::Corrupt("invalid adaptive table"));
+    }
+
+    let mut lengths = vec![0u8; 256];
+    for _ in 0..symbol_count {
+        let value = read_bits(reader, 13)?;
+        let symbol = (value >> 5) as usize;
+        if lengths[symbol] != 0 {
+            return Err(Error::Corrupt("invalid adaptive table"));
+        }
+        lengths[symbol] = (value & 0x1F) as u8 + 1;
+    }
+
+    Ok(lengths)
+}
+
+pub fn decompress_adaptive(data: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    if take(data, &mut pos, 4)? != ADAPTIVE_MAGIC {
+        return Err(Error::Corrupt("not an adaptive container"));
+    }
+    let flags = take(data, &mut pos, 1)?[0];
+    let original_len = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+    let window = u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap()) as u64;
+
+    match flags {
+        0 => {}
+        huffman_compress::FLAG_STORED => {
+            if data.len() - pos != original_len as usize {
+                return Err(Error::Corrupt("stored content has the wrong length"));
+            }
+            return Ok(data[pos..].to_vec());
+        }
+        _ => return Err(Error::Corrupt("unknown adaptive container flags")),
+    }
+    if window == 0 {
+        return Err(Error::Corrupt("adaptive window is zero"));
+    }
+
+    let payload = &data[pos..];
+    // every byte takes at least one bit
+    if original_len > payload.len() as u64 * 8 {
+        return Err(Error::Corrupt("adaptive payload is truncated"));
+    }
+    let mut reader = BitReader::new(payload, BitOrder::MsbFirst);
+    let mut res: Vec<u8> = Vec::with_capacity(original_len as usize);
+    let mut decoder: Option<CanonicalDecoder> = None;
+    while (res.len() as u64) < original_len {
+        if read_bits(&mut reader, 1)? == 1 {
+            decoder = Some(CanonicalDecoder::from_lengths(&read_table(&mut reader)?));
+        }
+        let Some(decoder) = &decoder else {
+            return Err(Error::Corrupt("adaptive payload starts without a table"));
+        };
+
+        let n = (original_len - res.len() as u64).min(window);
+        for _ in 0..n {
+            let Ok(Some(symbol)) = decoder.read_symbol(&mut reader) else {
+                return Err(Error::Corrupt("adaptive payload is truncated"));
+            };
+            res.push(symbol);
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a few hours of logs whose vocabulary changes every 20000 bytes
+    fn drifting(len: usize) -> Vec<u8> {
+        let alphabets: [&[u8]; 3] = [b"abcdefgh \n", b"0123456789:.", b"ERRORWARN!? "];
+        let mut state: u32 = 5;
+        (0..len)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let alphabet = alphabets[(i / 20_000) % alphabets.len()];
+                alphabet[(state >> 16) as usize % alphabet.len()]
+            })
+            .collect()
+    }
+
+    fn table_count(compressed: &[u8]) -> usize {
+        // walks the stream the way the decoder does, counting rebuild bits
+        let window = u32::from_le_bytes(compressed[13..17].try_into().unwrap()) as u64;
+        let len = u64::from_le_bytes(compressed[5..13].try_into().unwrap());
+        let mut reader = BitReader::new(&compressed[17..], BitOrder::MsbFirst);
+        let mut decoder: Option<CanonicalDecoder> = None;
+        let mut tables = 0;
+        let mut done = 0;
+        while done < len {
+            if read_bits(&mut reader, 1).unwrap() == 1 {
+                tables += 1;
+                decoder = Some(CanonicalDecoder::from_lengths(
+                    &read_table(&mut reader).unwrap(),
+                ));
+            }
+            for _ in 0..(len - done).min(window) {
+                let decoder = decoder.as_ref().unwrap();
+                decoder.read_symbol(&mut reader).unwrap().unwrap();
+            }
+            done += window;
+        }
+        tables
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for rebuild in [Rebuild::Always, Rebuild::Drift(50)] {
+            for len in [0, 1, 999, 1000, 1001, 100_000] {
+                let content = drifting(len);
+                let compressed = compress_adaptive(&content, 1000, rebuild);
+                assert_eq!(decompress_adaptive(&compressed).unwrap(), content);
+            }
+        }
+
+        let incompressible: Vec<u8> = (0..=255u8).cycle().take(3000).collect();
+        let compressed = compress_adaptive(&incompressible, 1000, Rebuild::Always);
+        assert_eq!(compressed[4], huffman_compress::FLAG_STORED);
+        assert_eq!(decompress_adaptive(&compressed).unwrap(), incompressible);
+    }
+
+    #[test]
+    fn test_beats_one_table_on_drifting_data() {
+        let content = drifting(120_000);
+        let single = huffman_compress::compress(&content);
+        let adaptive = compress_adaptive(&content, 4096, Rebuild::Always);
+        assert!(adaptive.len() < single.len() * 9 / 10);
+    }
+
+    #[test]
+    fn test_drift_only_rebuilds_when_it_pays() {
+        let content = drifting(120_000);
+        let always = compress_adaptive(&content, 4096, Rebuild::Always);
+        let drift = compress_adaptive(&content, 4096, Rebuild::Drift(20));
+        assert_eq!(table_count(&always), 30);
+        // one table per change of vocabulary, give or take a window
+        let tables = table_count(&drift);
+        assert!((6..=12).contains(&tables), "{} tables", tables);
+        assert!(drift.len() < always.len());
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(decompress_adaptive(b"HFW1").is_err());
+        let content = drifting(10_000);
+        let compressed = compress_adaptive(&content, 1000, Rebuild::Always);
+        assert!(decompress_adaptive(&compressed[..compressed.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::byteio::{read_exact, read_u8, read_u16, read_u32, read_u64};
+use crate::crc32::crc32;
+use crate::error::{Error, Result};
+use crate::huffman_compress;
+
+// Layout of an archive:
+//
+// magic              4 bytes, "HFA1"
+// entry data         one blob per entry, back to back
+// central directory  entry count (u32) followed by one record per entry
+// trailer            directory offset (u64) and "HFAE"
+//
+// Every integer is little endian. A directory record is
+//
+// path length u16, path (utf-8, '/' separated), size u64, mtime u64,
+// mode u32, codec u8, crc32 u32, data offset u64, data length u64
+
+const ARCHIVE_MAGIC: &[u8; 4] = b"HFA1";
+const TRAILER_MAGIC: &[u8; 4] = b"HFAE";
+const TRAILER_LEN: u64 = 12;
+// the directory stores path lengths as u16
+const MAX_PATH_LEN: usize = u16::MAX as usize;
+// rwx for owner, group and other; setuid, setgid and sticky are never restored
+const PERMISSION_BITS: u32 = 0o777;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Stored = 0,
+    Huffman = 1,
+}
+
+impl Codec {
+    fn from_u8(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(Codec::Stored),
+            1 => Ok(Codec::Huffman),
+            _ => Err(Error::Corrupt("unknown archive codec")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Stored => "stored",
+            Codec::Huffman => "huffman",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64,
+    // seconds since the unix epoch
+    pub mtime: u64,
+    // unix permission bits
+    pub mode: u32,
+    pub codec: Codec,
+    // of the original content
+    pub crc32: u32,
+    offset: u64,
+    compressed_size: u64,
+}
+
+impl ArchiveEntry {
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+}
+
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(ARCHIVE_MAGIC)?;
+
+        Ok(ArchiveWriter {
+            writer,
+            offset: ARCHIVE_MAGIC.len() as u64,
+            entries: Vec::new(),
+        })
+    }
+
+    // Huffman code the content, unless that makes it bigger
+    pub fn add_entry(&mut self, path: &str, content: &[u8], mtime: u64, mode: u32) -> Result<()> {
+        if self.entries.iter().any(|e| e.path == path) {
+            return Err(Error::DuplicateEntry(path.to_string()));
+        }
+        check_entry_path(path)?;
+
+        let compressed = huffman_compress::compress(content);
+        let (codec, data) = if compressed.len() < content.len() {
+            (Codec::Huffman, compressed.as_slice())
+        } else {
+            (Codec::Stored, content)
+        };
+
+        self.writer.write_all(data)?;
+        self.entries.push(ArchiveEntry {
+            path: path.to_string(),
+            size: content.len() as u64,
+            mtime,
+            mode,
+            codec,
+            crc32: crc32(content),
+            offset: self.offset,
+            compressed_size: data.len() as u64,
+        });
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+
+    pub fn add_file(&mut self, path: &str, file_path: &Path) -> Result<()> {
+        let content = fs::read(file_path)?;
+        let metadata = fs::metadata(file_path)?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        self.add_entry(path, &content, mtime, permissions_of(&metadata))
+    }
+
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    // write the central directory and give back the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        let directory_offset = self.offset;
+
+        let mut directory: Vec<u8> = Vec::new();
+        directory.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            let path = entry.path.as_bytes();
+            directory.extend_from_slice(&(path.len() as u16).to_le_bytes());
+            directory.extend_from_slice(path);
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&entry.mtime.to_le_bytes());
+            directory.extend_from_slice(&entry.mode.to_le_bytes());
+            directory.push(entry.codec as u8);
+            directory.extend_from_slice(&entry.crc32.to_le_bytes());
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        }
+        directory.extend_from_slice(&directory_offset.to_le_bytes());
+        directory.extend_from_slice(TRAILER_MAGIC);
+
+        self.writer.write_all(&directory)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+pub struct Archive<R: Read + Seek> {
+    reader: R,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl Archive<BufReader<File>> {
+    pub fn open_file(path: &Path) -> Result<Self> {
+        Archive::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> Archive<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.seek(SeekFrom::Start(0))?;
+        read_exact(&mut reader, &mut magic)?;
+        if &magic != ARCHIVE_MAGIC {
+            return Err(Error::Corrupt("not an archive"));
+        }
+
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end < ARCHIVE_MAGIC.len() as u64 + TRAILER_LEN {
+            return Err(Error::Corrupt("archive is truncated"));
+        }
+        reader.seek(SeekFrom::Start(end - TRAILER_LEN))?;
+        let directory_offset = read_u64(&mut reader)?;
+        read_exact(&mut reader, &mut magic)?;
+        if &magic != TRAILER_MAGIC || directory_offset > end - TRAILER_LEN {
+            return Err(Error::Corrupt("archive trailer is damaged"));
+        }
+
+        reader.seek(SeekFrom::Start(directory_offset))?;
+        let count = read_u32(&mut reader)?;
+        let mut entries: Vec<ArchiveEntry> = Vec::new();
+        let mut paths: HashSet<String> = HashSet::new();
+        for _ in 0..count {
+            let mut path = vec![0u8; read_u16(&mut reader)? as usize];
+            read_exact(&mut reader, &mut path)?;
+            let Ok(path) = String::from_utf8(path) else {
+                return Err(Error::Corrupt("archive path is not utf-8"));
+            };
+
+            let entry = ArchiveEntry {
+                path,
+                size: read_u64(&mut reader)?,
+                mtime: read_u64(&mut reader)?,
+                mode: read_u32(&mut reader)?,
+                codec: Codec::from_u8(read_u8(&mut reader)?)?,
+                crc32: read_u32(&mut reader)?,
+                offset: read_u64(&mut reader)?,
+                compressed_size: read_u64(&mut reader)?,
+            };
+            if entry.offset.saturating_add(entry.compressed_size) > directory_offset {
+                return Err(Error::Corrupt("archive entry points past its data"));
+            }
+            // the later one would overwrite the earlier one when extracting
+            if !paths.insert(entry.path.clone()) {
+                return Err(Error::DuplicateEntry(entry.path));
+            }
+            entries.push(entry);
+        }
+
+        Ok(Archive { reader, entries })
+    }
+
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    pub fn find(&self, path: &str) -> Option<&ArchiveEntry> {
+        self.entries.iter().find(|e| e.path == path)
+    }
+
+    // decoded content, checked against the stored checksum
+    pub fn read_entry(&mut self, entry: &ArchiveEntry) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; entry.compressed_size as usize];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        read_exact(&mut self.reader, &mut data)?;
+
+        let content = match entry.codec {
+            Codec::Stored => data,
+            Codec::Huffman => huffman_compress::decompress(&data)?,
+        };
+
+        if content.len() as u64 != entry.size {
+            return Err(Error::Corrupt("archive entry has the wrong size"));
+        }
+        let actual = crc32(&content);
+        if actual != entry.crc32 {
+            return Err(Error::ChecksumMismatch {
+                expected: entry.crc32,
+                actual,
+            });
+        }
+
+        Ok(content)
+    }
+
+    // write one entry to `dest`, restoring its mtime and permissions
+    pub fn extract_entry(&mut self, path: &str, dest: &Path) -> Result<()> {
+        let Some(entry) = self.find(path).cloned() else {
+            return Err(Error::EntryNotFound(path.to_string()));
+        };
+
+        let content = self.read_entry(&entry)?;
+        write_with_metadata(dest, &content, &entry)
+    }
+
+    // recreate every entry below `dest_dir`
+    pub fn extract_all(&mut self, dest_dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut written: Vec<PathBuf> = Vec::new();
+
+        for entry in self.entries.clone() {
+            let dest = dest_dir.join(check_entry_path(&entry.path)?);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let content = self.read_entry(&entry)?;
+            write_with_metadata(&dest, &content, &entry)?;
+            written.push(dest);
+        }
+
+        Ok(written)
+    }
+}
+
+// Pack `files` into a new archive at `archive_path`. Entries are named after
+// the file name only, like `tar` would with `-C` on each parent.
+pub fn create_archive(archive_path: &Path, files: &[PathBuf]) -> Result<Vec<ArchiveEntry>> {
+    let mut named: Vec<(String, PathBuf)> = Vec::new();
+    for file in files {
+        let Some(name) = file.file_name().and_then(|n| n.to_str()) else {
+            return Err(Error::UnsafePath(file.display().to_string()));
+        };
+        named.push((name.to_string(), file.clone()));
+    }
+
+    create_archive_named(archive_path, &named)
+}
+
+// Pack (entry path, file) pairs into a new archive at `archive_path`.
+pub fn create_archive_named(
+    archive_path: &Path,
+    files: &[(String, PathBuf)],
+) -> Result<Vec<ArchiveEntry>> {
+    let mut writer = ArchiveWriter::new(BufWriter::new(File::create(archive_path)?))?;
+
+    for (name, file) in files {
+        writer.add_file(name, file)?;
+    }
+
+    let entries = writer.entries().to_vec();
+    writer.finish()?;
+
+    Ok(entries)
+}
+
+// Every regular file below `root`, relative to it and sorted. Symlinks are
+// not followed, so a link back up the tree cannot make this loop.
+pub fn walk_dir(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut pending: Vec<PathBuf> = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        for dir_entry in fs::read_dir(root.join(&relative))? {
+            let dir_entry = dir_entry?;
+            let file_type = dir_entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(relative.join(dir_entry.file_name()));
+            } else if file_type.is_file() {
+                files.push(relative.join(dir_entry.file_name()));
+            }
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+// '/' separated, the way archive entries spell paths
+pub fn entry_path(relative: &Path) -> Result<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for component in relative.components() {
+        match component.as_os_str().to_str() {
+            Some(part) if matches!(component, Component::Normal(_)) => parts.push(part),
+            _ => return Err(Error::UnsafePath(relative.display().to_string())),
+        }
+    }
+
+    let res = parts.join("/");
+    if res.len() > MAX_PATH_LEN {
+        return Err(Error::UnsafePath(relative.display().to_string()));
+    }
+
+    Ok(res)
+}
+
+// give `dest` the mtime and permissions of `src`
+pub fn copy_metadata(src: &Path, dest: &Path) -> Result<()> {
+    let metadata = fs::metadata(src)?;
+    if let Ok(mtime) = metadata.modified() {
+        File::options()
+            .write(true)
+            .open(dest)?
+            .set_modified(mtime)?;
+    }
+    set_permissions(dest, permissions_of(&metadata))
+}
+
+// Entry paths are relative and '/' separated. Anything that could escape the
+// extraction directory is refused, and so is anything too long for the
+// directory's u16 length field. Each path has only one spelling: no "." parts,
+// empty parts or trailing '/', so two entries extract to the same file only
+// if their paths are equal.
+fn check_entry_path(path: &str) -> Result<PathBuf> {
+    if path.len() > MAX_PATH_LEN {
+        return Err(Error::UnsafePath(path.to_string()));
+    }
+    let mut res = PathBuf::new();
+    let mut parts: Vec<&str> = Vec::new();
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => {
+                res.push(part);
+                parts.push(part.to_str().expect("parts of a str are utf-8"));
+            }
+            _ => return Err(Error::UnsafePath(path.to_string())),
+        }
+    }
+
+    if parts.is_empty() || parts.join("/") != path {
+        return Err(Error::UnsafePath(path.to_string()));
+    }
+
+    Ok(res)
+}
+
+fn write_with_metadata(dest: &Path, content: &[u8], entry: &ArchiveEntry) -> Result<()> {
+    let mut file = File::create(dest)?;
+    file.write_all(content)?;
+    file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+    set_permissions(dest, entry.mode)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn permissions_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & PERMISSION_BITS
+}
+
+#[cfg(not(unix))]
+fn permissions_of(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_permissions(dest: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(dest, fs::Permissions::from_mode(mode & PERMISSION_BITS))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_permissions(dest: &Path, mode: u32) -> Result<()> {
+    let mut permissions = fs::metadata(dest)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(dest, permissions)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn build_archive() -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        writer
+            .add_entry(
+                "config/app.toml",
+                "name = \"app\"\n".repeat(20).as_bytes(),
+                1_700_000_000,
+                0o644,
+            )
+            .unwrap();
+        writer
+            .add_entry("bin/run.sh", b"#!/bin/sh\n", 1_700_000_001, 0o4755)
+            .unwrap();
+        writer.add_entry("empty", b"", 0, 0o600).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_list_and_read() {
+        let mut archive = Archive::new(Cursor::new(build_archive())).unwrap();
+
+        let paths: Vec<&str> = archive.entries().iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["config/app.toml", "bin/run.sh", "empty"]);
+
+        let entry = archive.find("config/app.toml").unwrap().clone();
+        assert_eq!(entry.codec, Codec::Huffman);
+        assert_eq!(entry.mode, 0o644);
+        assert_eq!(entry.mtime, 1_700_000_000);
+        assert_eq!(
+            archive.read_entry(&entry).unwrap(),
+            "name = \"app\"\n".repeat(20).as_bytes()
+        );
+
+        // too short to gain anything from a code table
+        let entry = archive.find("bin/run.sh").unwrap().clone();
+        assert_eq!(entry.codec, Codec::Stored);
+        assert_eq!(archive.read_entry(&entry).unwrap(), b"#!/bin/sh\n");
+
+        assert!(archive.find("missing").is_none());
+    }
+
+    #[test]
+    fn test_detects_damage() {
+        let mut data = build_archive();
+        // flip a bit inside the stored "bin/run.sh" content
+        let mut archive = Archive::new(Cursor::new(data.clone())).unwrap();
+        let offset = archive.find("bin/run.sh").unwrap().offset as usize;
+        data[offset + 3] ^= 0x01;
+
+        archive = Archive::new(Cursor::new(data)).unwrap();
+        let entry = archive.find("bin/run.sh").unwrap().clone();
+        assert!(matches!(
+            archive.read_entry(&entry),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        assert!(Archive::new(Cursor::new(b"HFA1".to_vec())).is_err());
+        assert!(Archive::new(Cursor::new(b"nope".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_rejects_unsafe_paths() {
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        assert!(writer.add_entry("../evil", b"x", 0, 0o644).is_err());
+        assert!(writer.add_entry("/etc/passwd", b"x", 0, 0o644).is_err());
+        assert!(writer.add_entry("ok/file", b"x", 0, 0o644).is_ok());
+        assert!(writer.add_entry("ok/file", b"x", 0, 0o644).is_err());
+        // other spellings of "ok/file"
+        for path in ["ok/./file", "./ok/file", "ok//file", "ok/file/"] {
+            assert!(matches!(
+                writer.add_entry(path, b"x", 0, 0o644),
+                Err(Error::UnsafePath(_))
+            ));
+        }
+
+        let long = "a".repeat(MAX_PATH_LEN + 1);
+        assert!(writer.add_entry(&long, b"x", 0, 0o644).is_err());
+        assert!(entry_path(Path::new(&long)).is_err());
+        assert!(writer.add_entry(&long[1..], b"x", 0, 0o644).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_duplicate_entries_in_directory() {
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        writer.add_entry("aaaa", b"first", 0, 0o644).unwrap();
+        writer.add_entry("bbbb", b"second", 0, 0o644).unwrap();
+        let mut bytes = writer.finish().unwrap();
+
+        // rename the second entry to the first one's path in the directory
+        let len = bytes.len();
+        let directory_offset =
+            u64::from_le_bytes(bytes[len - 12..len - 4].try_into().unwrap()) as usize;
+        let at = directory_offset
+            + bytes[directory_offset..]
+                .windows(4)
+                .position(|w| w == b"bbbb")
+                .unwrap();
+        bytes[at..at + 4].copy_from_slice(b"aaaa");
+
+        assert!(matches!(
+            Archive::new(Cursor::new(bytes)),
+            Err(Error::DuplicateEntry(path)) if path == "aaaa"
+        ));
+    }
+
+    #[test]
+    fn test_walk_dir() {
+        let dir = std::env::temp_dir().join(format!("learn_walk_test_{}", std::process::id()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("top.txt"), b"1").unwrap();
+        fs::write(dir.join("a/b/deep.txt"), b"2").unwrap();
+        fs::write(dir.join("a/mid.txt"), b"3").unwrap();
+
+        let files = walk_dir(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("a/b/deep.txt"),
+                PathBuf::from("a/mid.txt"),
+                PathBuf::from("top.txt")
+            ]
+        );
+        assert_eq!(entry_path(&files[0]).unwrap(), "a/b/deep.txt");
+        assert!(entry_path(Path::new("../x")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let dir = std::env::temp_dir().join(format!("learn_archive_test_{}", std::process::id()));
+        let mut archive = Archive::new(Cursor::new(build_archive())).unwrap();
+
+        let written = archive.extract_all(&dir).unwrap();
+        assert_eq!(written.len(), 3);
+        assert_eq!(fs::read(dir.join("bin/run.sh")).unwrap(), b"#!/bin/sh\n");
+
+        let metadata = fs::metadata(dir.join("bin/run.sh")).unwrap();
+        let mtime = metadata
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(mtime.as_secs(), 1_700_000_001);
+        // the setuid bit stored in the archive is not restored
+        assert_eq!(permissions_of(&metadata), 0o755);
+
+        archive.extract_entry("empty", &dir.join("single")).unwrap();
+        assert_eq!(fs::read(dir.join("single")).unwrap(), b"");
+        assert!(matches!(
+            archive.extract_entry("missing", &dir.join("x")),
+            Err(Error::EntryNotFound(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
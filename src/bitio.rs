@@ -0,0 +1,339 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+// Bit level I/O with a 64-bit accumulator. Bits go in and come out either
+// most significant bit first (what the huffman container uses) or least
+// significant bit first (deflate style).
+//
+// The std only containers read their payloads through `BitReader` and
+// `CanonicalDecoder::read_index`. The huffman container's own decoders
+// (`decode_payload`, `decode_into`) stay on bit iterators: they have to build
+// without std, and these types sit on `io::Read` and `io::Write`.
+
+// widest value a single call can move; the accumulator keeps up to 7 bits of
+// a partial byte next to it
+pub const MAX_BITS: u32 = 56;
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+fn mask(n: u32) -> u64 {
+    if n >= 64 { u64::MAX } else { (1u64 << n) - 1 }
+}
+
+// Bits are buffered until `finish`, a writer dropped without it loses up to a
+// buffer's worth of them.
+#[must_use = "buffered bits are only written out by `finish`"]
+pub struct BitWriter<W: Write> {
+    writer: W,
+    order: BitOrder,
+    // `nbits` pending bits, right aligned for msb first and starting at bit 0
+    // for lsb first; always fewer than 8 between calls
+    acc: u64,
+    nbits: u32,
+    // BUFFER_SIZE bytes plus room for one full 8 byte store past the end
+    buf: Vec<u8>,
+    pos: usize,
+    bits_written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(writer: W, order: BitOrder) -> Self {
+        BitWriter {
+            writer,
+            order,
+            acc: 0,
+            nbits: 0,
+            buf: vec![0u8; BUFFER_SIZE + 8],
+            pos: 0,
+            bits_written: 0,
+        }
+    }
+
+    // write the low `n` bits of `value`
+    #[inline]
+    pub fn write_bits(&mut self, value: u64, n: u32) -> io::Result<()> {
+        assert!(n <= MAX_BITS, "cannot write {} bits at once", n);
+        let value = value & mask(n);
+
+        // Whole bytes leave the accumulator as one 8 byte store; only the
+        // position moves by the number of complete bytes, the rest of the
+        // store is overwritten by the next one.
+        match self.order {
+            BitOrder::MsbFirst => {
+                self.acc = (self.acc << n) | value;
+                self.nbits += n;
+                if self.nbits >= 8 {
+                    let nbytes = (self.nbits / 8) as usize;
+                    self.nbits %= 8;
+                    let word = (self.acc >> self.nbits) << (64 - nbytes * 8);
+                    self.buf[self.pos..self.pos + 8].copy_from_slice(&word.to_be_bytes());
+                    self.pos += nbytes;
+                    self.acc &= mask(self.nbits);
+                }
+            }
+            BitOrder::LsbFirst => {
+                self.acc |= value << self.nbits;
+                self.nbits += n;
+                if self.nbits >= 8 {
+                    let nbytes = (self.nbits / 8) as usize;
+                    self.nbits %= 8;
+                    self.buf[self.pos..self.pos + 8].copy_from_slice(&self.acc.to_le_bytes());
+                    self.pos += nbytes;
+                    self.acc >>= nbytes * 8;
+                }
+            }
+        }
+        self.bits_written += n as u64;
+
+        if self.pos >= BUFFER_SIZE {
+            self.writer.write_all(&self.buf[..self.pos])?;
+            self.pos = 0;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        self.write_bits(bit as u64, 1)
+    }
+
+    // pad with zero bits up to the next byte boundary
+    pub fn align_to_byte(&mut self) -> io::Result<()> {
+        if self.nbits > 0 {
+            self.write_bits(0, 8 - self.nbits)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.nbits == 0
+    }
+
+    pub fn bits_written(&self) -> u64 {
+        self.bits_written
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    // pad the last byte, push everything out and give back the writer; has to
+    // be called, dropping the writer throws away whatever is still buffered
+    pub fn finish(mut self) -> io::Result<W> {
+        self.align_to_byte()?;
+        self.writer.write_all(&self.buf[..self.pos])?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+pub struct BitReader<R: Read> {
+    reader: R,
+    order: BitOrder,
+    // `nbits` unread bits, laid out like in `BitWriter`
+    acc: u64,
+    nbits: u32,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    bits_read: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(reader: R, order: BitOrder) -> Self {
+        BitReader {
+            reader,
+            order,
+            acc: 0,
+            nbits: 0,
+            buf: vec![0u8; BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            bits_read: 0,
+        }
+    }
+
+    // top the accumulator up to more than MAX_BITS bits, unless the input ends
+    fn refill(&mut self) -> io::Result<()> {
+        while self.nbits <= MAX_BITS {
+            if self.pos == self.len {
+                self.len = loop {
+                    match self.reader.read(&mut self.buf) {
+                        Ok(n) => break n,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                };
+                self.pos = 0;
+                if self.len == 0 {
+                    return Ok(());
+                }
+            }
+
+            let byte = self.buf[self.pos] as u64;
+            self.pos += 1;
+            match self.order {
+                BitOrder::MsbFirst => self.acc = (self.acc << 8) | byte,
+                BitOrder::LsbFirst => self.acc |= byte << self.nbits,
+            }
+            self.nbits += 8;
+        }
+
+        Ok(())
+    }
+
+    // The next `n` bits without consuming them. Past the end of the input
+    // the missing bits read as zero, so table driven decoders can always
+    // look ahead by their full width.
+    pub fn peek_bits(&mut self, n: u32) -> io::Result<u64> {
+        assert!(n <= MAX_BITS, "cannot peek {} bits at once", n);
+        if self.nbits < n {
+            self.refill()?;
+        }
+
+        let value = match self.order {
+            BitOrder::MsbFirst if self.nbits >= n => self.acc >> (self.nbits - n),
+            BitOrder::MsbFirst => self.acc << (n - self.nbits),
+            BitOrder::LsbFirst => self.acc,
+        };
+
+        Ok(value & mask(n))
+    }
+
+    // drop `n` bits that have already been peeked at
+    pub fn consume(&mut self, n: u32) -> io::Result<()> {
+        if self.nbits < n {
+            self.refill()?;
+            if self.nbits < n {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        self.nbits -= n;
+        match self.order {
+            BitOrder::MsbFirst => self.acc &= mask(self.nbits),
+            BitOrder::LsbFirst => self.acc = if n >= 64 { 0 } else { self.acc >> n },
+        }
+        self.bits_read += n as u64;
+
+        Ok(())
+    }
+
+    pub fn read_bits(&mut self, n: u32) -> io::Result<u64> {
+        let value = self.peek_bits(n)?;
+        self.consume(n)?;
+
+        Ok(value)
+    }
+
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    // skip whatever is left of the current byte
+    pub fn align_to_byte(&mut self) -> io::Result<()> {
+        let partial = (self.bits_read % 8) as u32;
+        if partial > 0 {
+            self.consume(8 - partial)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.bits_read.is_multiple_of(8)
+    }
+
+    pub fn bits_read(&self) -> u64 {
+        self.bits_read
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_order() {
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::MsbFirst);
+        writer.write_bits(0b101, 3).unwrap();
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0xABC, 12).unwrap();
+        assert_eq!(writer.bits_written(), 16);
+        assert_eq!(writer.finish().unwrap(), vec![0b1011_1010, 0b1011_1100]);
+
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::LsbFirst);
+        writer.write_bits(0b101, 3).unwrap();
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0xABC, 12).unwrap();
+        assert_eq!(writer.finish().unwrap(), vec![0b1100_1101, 0b1010_1011]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // (value, width) pairs with every width from 1 to MAX_BITS
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let fields: Vec<(u64, u32)> = (0..2000)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let n = (i % MAX_BITS as usize) as u32 + 1;
+                (state & mask(n), n)
+            })
+            .collect();
+
+        for order in [BitOrder::MsbFirst, BitOrder::LsbFirst] {
+            let mut writer = BitWriter::new(Vec::new(), order);
+            for &(value, n) in &fields {
+                writer.write_bits(value, n).unwrap();
+            }
+            let bytes = writer.finish().unwrap();
+
+            let mut reader = BitReader::new(bytes.as_slice(), order);
+            for &(value, n) in &fields {
+                assert_eq!(reader.peek_bits(n).unwrap(), value);
+                assert_eq!(reader.read_bits(n).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_alignment() {
+        for order in [BitOrder::MsbFirst, BitOrder::LsbFirst] {
+            let mut writer = BitWriter::new(Vec::new(), order);
+            writer.write_bits(0b11, 2).unwrap();
+            assert!(!writer.is_aligned());
+            writer.align_to_byte().unwrap();
+            assert!(writer.is_aligned());
+            writer.write_bits(0x5A, 8).unwrap();
+            let bytes = writer.finish().unwrap();
+            assert_eq!(bytes.len(), 2);
+            assert_eq!(bytes[1], 0x5A);
+
+            let mut reader = BitReader::new(bytes.as_slice(), order);
+            assert_eq!(reader.read_bits(2).unwrap(), 0b11);
+            reader.align_to_byte().unwrap();
+            assert_eq!(reader.bits_read(), 8);
+            assert_eq!(reader.read_bits(8).unwrap(), 0x5A);
+        }
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        let mut reader = BitReader::new(&[0xF0u8][..], BitOrder::MsbFirst);
+        // peeking past the end pads with zeros
+        assert_eq!(reader.peek_bits(12).unwrap(), 0xF00);
+        assert_eq!(reader.read_bits(6).unwrap(), 0b111100);
+        assert_eq!(
+            reader.read_bits(3).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(reader.read_bits(2).unwrap(), 0);
+    }
+}
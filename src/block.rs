@@ -0,0 +1,570 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::byteio::{read_exact, read_u8, read_u32, read_u64};
+use crate::context_model;
+use crate::error::{Error, Result};
+use crate::huffman_compress::{self, CanonicalDecoder, PackedCodeTable};
+use crate::parallel;
+use crate::progress::Hooks;
+
+// Layout of a block file:
+//
+// magic        4 bytes, "HFB1"
+// block size   u32
+// table mode   u8
+// shared table code lengths (see `write_code_lengths`), only in shared mode
+// blocks       back to back
+// index        original length u64, block count u32, then block count + 1
+//              offsets (u64) from the start of the file, the last one being
+//              where the blocks end
+// trailer      index offset (u64) and "HFBE"
+//
+// In per-block mode every block is a complete huffman container, in order-1
+// mode a complete order-1 context model container. In shared
+// mode a block is a flag byte followed by either the coded bits of its bytes,
+// padded to a byte, or, when coding would not make it smaller, the bytes as
+// they are. Its length follows from the block size.
+
+const BLOCK_MAGIC: &[u8; 4] = b"HFB1";
+const TRAILER_MAGIC: &[u8; 4] = b"HFBE";
+const TRAILER_LEN: u64 = 12;
+// first byte of a shared mode block
+const SHARED_CODED: u8 = 0;
+const SHARED_STORED: u8 = 1;
+
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableMode {
+    // every block carries its own code table
+    PerBlock = 0,
+    // one table for the whole input, stored in the header
+    Shared = 1,
+    // every block is coded with its own order-1 context model
+    Order1 = 2,
+}
+
+impl TableMode {
+    fn from_u8(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(TableMode::PerBlock),
+            1 => Ok(TableMode::Shared),
+            2 => Ok(TableMode::Order1),
+            _ => Err(Error::Corrupt("unknown block table mode")),
+        }
+    }
+}
+
+// What goes before the blocks, plus whatever the blocks need to be encoded.
+pub(crate) struct BlockHeader {
+    pub(crate) bytes: Vec<u8>,
+    mode: TableMode,
+    shared_table: Option<PackedCodeTable>,
+}
+
+pub(crate) fn build_header(content: &[u8], block_size: usize, mode: TableMode) -> BlockHeader {
+    assert!(
+        block_size > 0 && block_size <= u32::MAX as usize,
+        "invalid block size"
+    );
+
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(BLOCK_MAGIC);
+    bytes.extend_from_slice(&(block_size as u32).to_le_bytes());
+    bytes.push(mode as u8);
+
+    let shared_table = match mode {
+        TableMode::PerBlock | TableMode::Order1 => None,
+        TableMode::Shared => {
+            let lengths =
+                huffman_compress::build_code_lengths(&huffman_compress::count_frequency(content));
+            huffman_compress::write_code_lengths(&mut bytes, &lengths);
+            Some(huffman_compress::packed_table_from_lengths(&lengths))
+        }
+    };
+
+    BlockHeader {
+        bytes,
+        mode,
+        shared_table,
+    }
+}
+
+impl BlockHeader {
+    pub(crate) fn encode_block(&self, block: &[u8]) -> Vec<u8> {
+        match (self.mode, &self.shared_table) {
+            (TableMode::Order1, _) => context_model::compress_order1(block),
+            (_, None) => huffman_compress::compress(block),
+            (_, Some(table)) => encode_shared(block, table),
+        }
+    }
+}
+
+fn encode_shared(block: &[u8], table: &PackedCodeTable) -> Vec<u8> {
+    let frequency = huffman_compress::count_frequency(block);
+    let bits = huffman_compress::estimate_payload_bits(&frequency, table)
+        .expect("content in memory codes to fewer than u64::MAX bits");
+    if bits.div_ceil(8) >= block.len() as u64 {
+        let mut res = vec![SHARED_STORED];
+        res.extend_from_slice(block);
+        return res;
+    }
+
+    let mut res = vec![SHARED_CODED];
+    res.extend_from_slice(&huffman_compress::encode_payload(block, table));
+    res
+}
+
+// `offsets` are where each block starts, the last one where the blocks end,
+// which is also where the index goes
+pub(crate) fn write_index(out: &mut Vec<u8>, original_len: u64, offsets: &[u64]) {
+    let index_offset = *offsets
+        .last()
+        .expect("offsets include the end of the blocks");
+    out.extend_from_slice(&original_len.to_le_bytes());
+    out.extend_from_slice(&((offsets.len() - 1) as u32).to_le_bytes());
+    for offset in offsets {
+        out.extend_from_slice(&offset.to_le_bytes());
+    }
+    out.extend_from_slice(&index_offset.to_le_bytes());
+    out.extend_from_slice(TRAILER_MAGIC);
+}
+
+pub fn compress_blocks(content: &[u8], block_size: usize, mode: TableMode) -> Vec<u8> {
+    let header = build_header(content, block_size, mode);
+    let mut res = header.bytes.clone();
+
+    let mut offsets: Vec<u64> = Vec::new();
+    for block in content.chunks(block_size) {
+        offsets.push(res.len() as u64);
+        res.extend_from_slice(&header.encode_block(block));
+    }
+    offsets.push(res.len() as u64);
+
+    write_index(&mut res, content.len() as u64, &offsets);
+
+    res
+}
+
+// Writes a block file as the input comes in, so only one block is held at a
+// time. The shared table is built from the whole input, which a writer never
+// sees, so it only does the per-block and order-1 modes.
+pub struct BlockWriter<W: Write> {
+    writer: W,
+    header: BlockHeader,
+    block_size: usize,
+    pending: Vec<u8>,
+    offsets: Vec<u64>,
+    original_len: u64,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(mut writer: W, block_size: usize, mode: TableMode) -> Result<Self> {
+        if mode == TableMode::Shared {
+            return Err(Error::Unsupported(
+                "a shared table needs the whole input up front",
+            ));
+        }
+        let header = build_header(&[], block_size, mode);
+        writer.write_all(&header.bytes)?;
+
+        Ok(BlockWriter {
+            writer,
+            offsets: vec![header.bytes.len() as u64],
+            header,
+            block_size,
+            pending: Vec::with_capacity(block_size),
+            original_len: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let encoded = self.header.encode_block(&self.pending);
+        self.writer.write_all(&encoded)?;
+        self.offsets
+            .push(self.offsets.last().unwrap() + encoded.len() as u64);
+        self.original_len += self.pending.len() as u64;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    // writes the last, short block and the index
+    pub fn finish(mut self) -> Result<W> {
+        if !self.pending.is_empty() {
+            self.write_block()?;
+        }
+
+        let mut index: Vec<u8> = Vec::new();
+        write_index(&mut index, self.original_len, &self.offsets);
+        self.writer.write_all(&index)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.block_size - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        if self.pending.len() == self.block_size {
+            self.write_block()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub fn decompress_blocks(data: &[u8]) -> Result<Vec<u8>> {
+    decompress_blocks_with_hooks(data, 1, &mut Hooks::new())
+}
+
+// `decompress_blocks` on `workers` threads, reporting to and checking `hooks`
+pub fn decompress_blocks_with_hooks(
+    data: &[u8],
+    workers: usize,
+    hooks: &mut Hooks,
+) -> Result<Vec<u8>> {
+    let mut reader = BlockReader::new(std::io::Cursor::new(data))?;
+
+    // `BlockReader::new` has checked the length against the blocks
+    let mut res: Vec<u8> = Vec::with_capacity(reader.len() as usize);
+    parallel::decompress_blocks_parallel(&mut reader, workers, &mut res, hooks)?;
+
+    Ok(res)
+}
+
+// Everything needed to decode a block once its bytes have been read. Kept
+// apart from the reader so blocks can be decoded on other threads.
+#[derive(Clone)]
+pub(crate) struct BlockDecoder {
+    block_size: u64,
+    original_len: u64,
+    mode: TableMode,
+    shared_decoder: Option<CanonicalDecoder>,
+}
+
+impl BlockDecoder {
+    pub(crate) fn decode_block(&self, i: usize, data: &[u8]) -> Result<Vec<u8>> {
+        let expected = self
+            .block_size
+            .min(self.original_len - i as u64 * self.block_size);
+
+        let block = match &self.shared_decoder {
+            None if self.mode == TableMode::Order1 => context_model::decompress_order1(data)?,
+            None => huffman_compress::decompress(data)?,
+            Some(decoder) => match data.split_first() {
+                Some((&SHARED_STORED, stored)) => stored.to_vec(),
+                Some((&SHARED_CODED, payload)) => {
+                    huffman_compress::decode_payload(payload, decoder, expected)?
+                }
+                _ => return Err(Error::Corrupt("unknown shared block flag")),
+            },
+        };
+        if block.len() as u64 != expected {
+            return Err(Error::Corrupt("block has the wrong length"));
+        }
+
+        Ok(block)
+    }
+}
+
+// Random access into a block file: only the header and index are read up
+// front, blocks are decoded when a read touches them.
+pub struct BlockReader<R: Read + Seek> {
+    reader: R,
+    decoder: BlockDecoder,
+    offsets: Vec<u64>,
+}
+
+impl BlockReader<BufReader<File>> {
+    pub fn open_file(path: &Path) -> Result<Self> {
+        BlockReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> BlockReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.seek(SeekFrom::Start(0))?;
+        read_exact(&mut reader, &mut magic)?;
+        if &magic != BLOCK_MAGIC {
+            return Err(Error::Corrupt("not a block file"));
+        }
+        let block_size = read_u32(&mut reader)? as u64;
+        if block_size == 0 {
+            return Err(Error::Corrupt("block size is zero"));
+        }
+        let mode = TableMode::from_u8(read_u8(&mut reader)?)?;
+
+        let shared_decoder = match mode {
+            TableMode::PerBlock | TableMode::Order1 => None,
+            TableMode::Shared => {
+                // the table is at most 2 + 256 * 2 bytes
+                let start = reader.stream_position()?;
+                let mut table: Vec<u8> = Vec::new();
+                reader.by_ref().take(2 + 512).read_to_end(&mut table)?;
+                let mut pos = 0;
+                let lengths = huffman_compress::read_code_lengths(&table, &mut pos)?;
+                reader.seek(SeekFrom::Start(start + pos as u64))?;
+                Some(CanonicalDecoder::from_lengths(&lengths))
+            }
+        };
+        let header_end = reader.stream_position()?;
+
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end < header_end + TRAILER_LEN {
+            return Err(Error::Corrupt("block file is truncated"));
+        }
+        reader.seek(SeekFrom::Start(end - TRAILER_LEN))?;
+        let index_offset = read_u64(&mut reader)?;
+        read_exact(&mut reader, &mut magic)?;
+        if &magic != TRAILER_MAGIC || index_offset < header_end || index_offset > end - TRAILER_LEN
+        {
+            return Err(Error::Corrupt("block file trailer is damaged"));
+        }
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let original_len = read_u64(&mut reader)?;
+        let block_count = read_u32(&mut reader)? as u64;
+        if block_count != original_len.div_ceil(block_size)
+            || (block_count + 1) * 8 + 12 != end - TRAILER_LEN - index_offset
+        {
+            return Err(Error::Corrupt("block index does not match the content"));
+        }
+        // every byte takes at least one bit, so the blocks bound what they
+        // can decode to; callers size buffers by `len` before decoding
+        if original_len > (index_offset - header_end).saturating_mul(8) {
+            return Err(Error::Corrupt("blocks cannot hold the original length"));
+        }
+
+        let mut offsets: Vec<u64> = Vec::with_capacity(block_count as usize + 1);
+        for _ in 0..=block_count {
+            let offset = read_u64(&mut reader)?;
+            if offset < offsets.last().copied().unwrap_or(header_end) || offset > index_offset {
+                return Err(Error::Corrupt("block index is damaged"));
+            }
+            offsets.push(offset);
+        }
+
+        Ok(BlockReader {
+            reader,
+            decoder: BlockDecoder {
+                block_size,
+                original_len,
+                mode,
+                shared_decoder,
+            },
+            offsets,
+        })
+    }
+
+    pub fn table_mode(&self) -> TableMode {
+        self.decoder.mode
+    }
+
+    // length of the original content
+    pub fn len(&self) -> u64 {
+        self.decoder.original_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decoder.original_len == 0
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.decoder.block_size
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    // compressed bytes of block `i`, as stored in the file
+    pub(crate) fn raw_block(&mut self, i: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; (self.offsets[i + 1] - self.offsets[i]) as usize];
+        self.reader.seek(SeekFrom::Start(self.offsets[i]))?;
+        read_exact(&mut self.reader, &mut data)?;
+
+        Ok(data)
+    }
+
+    pub(crate) fn decoder(&self) -> &BlockDecoder {
+        &self.decoder
+    }
+
+    pub fn read_block(&mut self, i: usize) -> Result<Vec<u8>> {
+        let data = self.raw_block(i)?;
+        self.decoder.decode_block(i, &data)
+    }
+
+    // Up to `len` bytes starting at `offset` of the original content. Reads
+    // past the end are cut short, like `Read::read` would.
+    pub fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let block_size = self.block_size();
+        let end = offset.saturating_add(len as u64).min(self.len());
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        let first = (offset / block_size) as usize;
+        let last = ((end - 1) / block_size) as usize;
+
+        let mut res: Vec<u8> = Vec::with_capacity((end - offset) as usize);
+        for i in first..=last {
+            let block = self.read_block(i)?;
+            let block_start = i as u64 * block_size;
+            let from = offset.saturating_sub(block_start) as usize;
+            let to = ((end - block_start) as usize).min(block.len());
+            res.extend_from_slice(&block[from..to]);
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample(len: usize) -> Vec<u8> {
+        // text-ish, with a change of alphabet half way through
+        (0..len)
+            .map(|i| {
+                if i < len / 2 {
+                    b"abcdefg\n"[i % 8]
+                } else {
+                    b"kkkkghfd"[i % 7]
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for mode in [TableMode::PerBlock, TableMode::Shared, TableMode::Order1] {
+            for len in [0, 1, 99, 100, 101, 1000] {
+                let content = sample(len);
+                let compressed = compress_blocks(&content, 100, mode);
+                assert_eq!(decompress_blocks(&compressed).unwrap(), content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_mode_stores_incompressible_blocks() {
+        // the second block uses every byte value evenly, the table built for
+        // the whole input cannot code it in less than 8 bits a byte
+        let mut content = b"aaaaaaab".repeat(32);
+        content.extend((0..=255u8).cycle().take(256));
+
+        let compressed = compress_blocks(&content, 256, TableMode::Shared);
+        let mut reader = BlockReader::new(Cursor::new(compressed)).unwrap();
+        let stored = reader.raw_block(1).unwrap();
+        assert_eq!(stored[0], SHARED_STORED);
+        assert_eq!(stored[1..], content[256..]);
+        let coded = reader.raw_block(0).unwrap();
+        assert_eq!(coded[0], SHARED_CODED);
+        assert!(coded.len() < 256);
+        assert_eq!(reader.read_at(250, 20).unwrap(), &content[250..270]);
+    }
+
+    #[test]
+    fn test_read_at() {
+        let content = sample(1000);
+
+        for mode in [TableMode::PerBlock, TableMode::Shared, TableMode::Order1] {
+            let compressed = compress_blocks(&content, 64, mode);
+            let mut reader = BlockReader::new(Cursor::new(compressed)).unwrap();
+            assert_eq!(reader.len(), 1000);
+            assert_eq!(reader.block_count(), 16);
+
+            assert_eq!(reader.read_at(0, 10).unwrap(), &content[0..10]);
+            assert_eq!(reader.read_at(60, 10).unwrap(), &content[60..70]);
+            assert_eq!(reader.read_at(100, 500).unwrap(), &content[100..600]);
+            assert_eq!(reader.read_at(990, 100).unwrap(), &content[990..]);
+            assert!(reader.read_at(1000, 10).unwrap().is_empty());
+            assert!(reader.read_at(5, 0).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_read_at_only_decodes_needed_blocks() {
+        let content = sample(1000);
+        let mut compressed = compress_blocks(&content, 100, TableMode::PerBlock);
+
+        // damage the first block, reads elsewhere must still work
+        let mut reader = BlockReader::new(Cursor::new(compressed.clone())).unwrap();
+        let first_block = reader.offsets[0] as usize;
+        compressed[first_block] ^= 0xFF;
+
+        reader = BlockReader::new(Cursor::new(compressed)).unwrap();
+        assert_eq!(reader.read_at(500, 50).unwrap(), &content[500..550]);
+        assert!(reader.read_at(0, 50).is_err());
+    }
+
+    #[test]
+    fn test_writer_matches_compress_blocks() {
+        let content = sample(1000);
+        for mode in [TableMode::PerBlock, TableMode::Order1] {
+            let mut writer = BlockWriter::new(Vec::new(), 100, mode).unwrap();
+            for piece in content.chunks(37) {
+                writer.write_all(piece).unwrap();
+            }
+            let written = writer.finish().unwrap();
+            assert_eq!(written, compress_blocks(&content, 100, mode));
+        }
+
+        let empty = BlockWriter::new(Vec::new(), 100, TableMode::PerBlock).unwrap();
+        let written = empty.finish().unwrap();
+        assert!(decompress_blocks(&written).unwrap().is_empty());
+
+        assert!(matches!(
+            BlockWriter::new(Vec::new(), 100, TableMode::Shared),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_damaged_index() {
+        let mut compressed = compress_blocks(&sample(1000), 100, TableMode::Shared);
+        let len = compressed.len();
+        compressed[len - 1] = b'X';
+        assert!(BlockReader::new(Cursor::new(compressed)).is_err());
+        assert!(BlockReader::new(Cursor::new(b"HFB1".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_rejects_huge_original_length() {
+        // with 4 GiB blocks a trailer can claim 4 GiB for a single block
+        let mut compressed = compress_blocks(&sample(1000), u32::MAX as usize, TableMode::PerBlock);
+        let len = compressed.len();
+        let index_offset =
+            u64::from_le_bytes(compressed[len - 12..len - 4].try_into().unwrap()) as usize;
+        compressed[index_offset..index_offset + 8]
+            .copy_from_slice(&(u32::MAX as u64).to_le_bytes());
+
+        // turned down before anything is allocated for it
+        assert!(matches!(
+            BlockReader::new(Cursor::new(&compressed)),
+            Err(Error::Corrupt(_))
+        ));
+        assert!(decompress_blocks(&compressed).is_err());
+    }
+}
@@ -1,9 +1,9 @@
 use std::io::Write;
 
 struct BstreeNode<T> {
-    pub val: T,
-    pub left: Option<Box<BstreeNode<T>>>,
-    pub right: Option<Box<BstreeNode<T>>>,
+    val: T,
+    left: Option<Box<BstreeNode<T>>>,
+    right: Option<Box<BstreeNode<T>>>,
 }
 
 impl<T: std::cmp::Ord> BstreeNode<T> {
@@ -176,6 +176,12 @@ pub struct Bstree<T> {
     root: Option<Box<BstreeNode<T>>>,
 }
 
+impl<T: std::cmp::Ord> Default for Bstree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: std::cmp::Ord> Bstree<T> {
     pub fn new() -> Self {
         Bstree { root: None }
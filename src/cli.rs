@@ -0,0 +1,905 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use learn::adaptive::{self, Rebuild};
+use learn::archive;
+use learn::crc32::crc32;
+use learn::huffman_compress::{self, FLAG_CHECKSUM, FLAG_STORED};
+use learn::options::{self, CompressionOptions, Format, MAX_LEVEL, MIN_LEVEL};
+use learn::progress::{Hooks, Progress};
+use learn::resync;
+
+pub type CliResult = Result<(), Box<dyn Error>>;
+
+// Bad arguments. `main` prints the usage it carries and exits with 2.
+#[derive(Debug)]
+pub struct UsageError(pub &'static str);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+pub const COMPRESS_USAGE: &str = "usage: learn compress [-r] <in> [-o <out>] [-f]
+    [-l <1-9>] [-b <block KiB, 0 for none>] [-j <workers>] [-s <sync interval>]
+    [-w <table rebuild window KiB>] [--drift <per mille>]";
+pub const DECOMPRESS_USAGE: &str = "usage: learn decompress [-r] <in> [-o <out>] [-f]";
+pub const INSPECT_USAGE: &str = "usage: learn inspect <in>";
+pub const VERIFY_USAGE: &str = "usage: learn verify <in>";
+pub const ARCHIVE_USAGE: &str = "usage:
+    learn archive create <archive> <file or dir>...
+    learn archive list <archive>
+    learn archive extract <archive> <entry> [dest]
+    learn archive extract-all <archive> [dest_dir]";
+
+// what `compress` appends to the input name when no output is given
+pub const EXTENSION: &str = ".hfc";
+
+// not produced here, but skipped like EXTENSION when walking a tree
+pub const ARCHIVE_EXTENSION: &str = ".hfa";
+
+// stands for stdin or stdout
+const STDIO: &str = "-";
+
+#[derive(Debug, PartialEq, Eq)]
+struct IoArgs {
+    input: String,
+    output: Option<String>,
+    force: bool,
+    // `input` and `output` are directories
+    recursive: bool,
+    // only set by compress
+    options: CompressionOptions,
+}
+
+fn parse_number<T: std::str::FromStr>(
+    arg: Option<&String>,
+    usage: &'static str,
+) -> Result<T, UsageError> {
+    arg.and_then(|a| a.parse().ok()).ok_or(UsageError(usage))
+}
+
+// `tuning` accepts the flags that set compression options
+fn parse_io_args(args: &[String], usage: &'static str, tuning: bool) -> Result<IoArgs, UsageError> {
+    let mut input: Option<String> = None;
+    let mut output: Option<String> = None;
+    let mut force = false;
+    let mut recursive = false;
+    let mut options = CompressionOptions::builder();
+    let mut rebuild_window: Option<usize> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" if output.is_none() => match args.next() {
+                Some(out) => output = Some(out.clone()),
+                None => return Err(UsageError(usage)),
+            },
+            "-f" | "--force" => force = true,
+            "-r" | "--recursive" => recursive = true,
+            "-l" | "--level" if tuning => {
+                let level: u8 = parse_number(args.next(), usage)?;
+                if !(MIN_LEVEL..=MAX_LEVEL).contains(&level) {
+                    return Err(UsageError(usage));
+                }
+                options = options.level(level);
+            }
+            "-b" | "--block-size" if tuning => {
+                let kib: usize = parse_number(args.next(), usage)?;
+                let block_size = kib
+                    .checked_mul(1024)
+                    .filter(|&size| size <= u32::MAX as usize);
+                if kib > 0 && block_size.is_none() {
+                    return Err(UsageError(usage));
+                }
+                options = options.block_size(block_size.filter(|&size| size > 0));
+            }
+            "-s" | "--sync" if tuning => {
+                let interval: u32 = parse_number(args.next(), usage)?;
+                if interval == 0 {
+                    return Err(UsageError(usage));
+                }
+                options = options.sync_interval(Some(interval));
+            }
+            "-w" | "--window" if tuning => {
+                let kib: usize = parse_number(args.next(), usage)?;
+                let window = kib
+                    .checked_mul(1024)
+                    .filter(|&size| size > 0 && size <= u32::MAX as usize);
+                if window.is_none() {
+                    return Err(UsageError(usage));
+                }
+                rebuild_window = window;
+            }
+            // only rebuild when the table has drifted this far
+            "--drift" if tuning => {
+                options = options.rebuild(Rebuild::Drift(parse_number(args.next(), usage)?));
+                rebuild_window = rebuild_window.or(Some(adaptive::DEFAULT_WINDOW));
+            }
+            "-j" | "--workers" if tuning => {
+                let workers: usize = parse_number(args.next(), usage)?;
+                if workers == 0 {
+                    return Err(UsageError(usage));
+                }
+                options = options.workers(workers);
+            }
+            a if (a == STDIO || !a.starts_with('-')) && input.is_none() => {
+                input = Some(a.to_string())
+            }
+            _ => return Err(UsageError(usage)),
+        }
+    }
+
+    let Some(input) = input else {
+        return Err(UsageError(usage));
+    };
+    if recursive && (input == STDIO || output.as_deref() == Some(STDIO)) {
+        return Err(UsageError(usage));
+    }
+
+    Ok(IoArgs {
+        input,
+        output,
+        force,
+        recursive,
+        options: options.rebuild_window(rebuild_window).build(),
+    })
+}
+
+fn single_input(args: &[String], usage: &'static str) -> Result<String, UsageError> {
+    match args {
+        [input] if input == STDIO || !input.starts_with('-') => Ok(input.clone()),
+        _ => Err(UsageError(usage)),
+    }
+}
+
+fn read_input(path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut content: Vec<u8> = Vec::new();
+    let read = if path == STDIO {
+        io::stdin().lock().read_to_end(&mut content)
+    } else {
+        OpenOptions::new()
+            .read(true)
+            .open(path)
+            .and_then(|mut file| file.read_to_end(&mut content))
+    };
+    if let Err(e) = read {
+        return Err(format!("cannot read {}: {}", path, e).into());
+    }
+
+    Ok(content)
+}
+
+fn write_output(path: &str, data: &[u8], force: bool) -> CliResult {
+    if path == STDIO {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(data)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    write_file(Path::new(path), data, force)
+}
+
+// existing files are only replaced with `force`
+fn write_file(path: &Path, data: &[u8], force: bool) -> CliResult {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(!force)
+        .truncate(true)
+        .open(path);
+    match file {
+        Ok(mut file) => Ok(file.write_all(data)?),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            Err(format!("{} already exists, use -f to overwrite it", path.display()).into())
+        }
+        Err(e) => Err(format!("cannot create {}: {}", path.display(), e).into()),
+    }
+}
+
+fn compressed_name(input: &str) -> String {
+    if input == STDIO {
+        return STDIO.to_string();
+    }
+    format!("{}{}", input, EXTENSION)
+}
+
+fn decompressed_name(input: &str) -> Option<String> {
+    if input == STDIO {
+        return Some(STDIO.to_string());
+    }
+    input
+        .strip_suffix(EXTENSION)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        return 100.0;
+    }
+    part as f64 * 100.0 / whole as f64
+}
+
+// our own outputs, which compressing again would only grow
+fn is_compressed_name(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|p| p.ends_with(EXTENSION) || p.ends_with(ARCHIVE_EXTENSION))
+}
+
+// running totals for the tree modes
+#[derive(Default)]
+struct Savings {
+    files: usize,
+    skipped: usize,
+    failed: usize,
+    before: u64,
+    after: u64,
+}
+
+impl Savings {
+    fn add(&mut self, before: u64, after: u64) {
+        self.files += 1;
+        self.before += before;
+        self.after += after;
+    }
+
+    fn skip(&mut self, path: &Path) {
+        println!("skipped {} (already compressed)", path.display());
+        self.skipped += 1;
+    }
+
+    fn fail(&mut self, path: &Path, e: Box<dyn Error>) {
+        eprintln!("error: {}: {}", path.display(), e);
+        self.failed += 1;
+    }
+
+    fn report(&self) -> CliResult {
+        println!(
+            "total: {} files, {} -> {} bytes ({:.1}%), saved {} bytes, {} skipped",
+            self.files,
+            self.before,
+            self.after,
+            percent(self.after as usize, self.before as usize),
+            self.before as i64 - self.after as i64,
+            self.skipped
+        );
+        if self.failed > 0 {
+            return Err(format!("{} files failed", self.failed).into());
+        }
+
+        Ok(())
+    }
+}
+
+fn compress_file(src: &Path, dest: &Path, args: &IoArgs) -> Result<(u64, u64), Box<dyn Error>> {
+    let content = fs::read(src)?;
+    let compressed = options::compress(&content, &args.options);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_file(dest, &compressed, args.force)?;
+    archive::copy_metadata(src, dest)?;
+
+    Ok((content.len() as u64, compressed.len() as u64))
+}
+
+// `<out>/<relative path>.hfc` for every file below `<in>`, `<out>` being
+// `<in>` itself unless -o says otherwise
+fn compress_tree(args: &IoArgs) -> CliResult {
+    let root = Path::new(&args.input);
+    let out_root = args.output.as_deref().map_or(root, Path::new);
+    let mut savings = Savings::default();
+
+    for relative in archive::walk_dir(root)? {
+        let src = root.join(&relative);
+        if is_compressed_name(&relative) {
+            savings.skip(&src);
+            continue;
+        }
+
+        let mut dest = out_root.join(&relative).into_os_string();
+        dest.push(EXTENSION);
+        let dest = PathBuf::from(dest);
+        match compress_file(&src, &dest, args) {
+            Ok((before, after)) => {
+                println!(
+                    "{}: {} -> {} bytes ({:.1}%)",
+                    src.display(),
+                    before,
+                    after,
+                    percent(after as usize, before as usize)
+                );
+                savings.add(before, after);
+            }
+            Err(e) => savings.fail(&src, e),
+        }
+    }
+
+    savings.report()
+}
+
+fn decompress_file(src: &Path, dest: &Path, force: bool) -> CliResult {
+    let content = options::decompress(&fs::read(src)?)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_file(dest, &content, force)?;
+    archive::copy_metadata(src, dest)?;
+
+    Ok(())
+}
+
+// the other way round; files without the extension are left alone
+fn decompress_tree(args: &IoArgs) -> CliResult {
+    let root = Path::new(&args.input);
+    let out_root = args.output.as_deref().map_or(root, Path::new);
+    let (mut restored, mut failed) = (0, 0);
+
+    for relative in archive::walk_dir(root)? {
+        let Some(name) = relative.to_str().and_then(decompressed_name) else {
+            continue;
+        };
+
+        let src = root.join(&relative);
+        let dest = out_root.join(name);
+        match decompress_file(&src, &dest, args.force) {
+            Ok(()) => {
+                println!("{} -> {}", src.display(), dest.display());
+                restored += 1;
+            }
+            Err(e) => {
+                eprintln!("error: {}: {}", src.display(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("total: {} files restored", restored);
+    if failed > 0 {
+        return Err(format!("{} files failed", failed).into());
+    }
+
+    Ok(())
+}
+
+// how often the progress line is redrawn at most
+const PROGRESS_REFRESH: Duration = Duration::from_millis(100);
+
+fn progress_line(p: &Progress) -> String {
+    let pct = match p.total_bytes {
+        0 => 100.0,
+        total => p.bytes_read as f64 * 100.0 / total as f64,
+    };
+    format!(
+        "{} {:.0}%: {} bytes read, {} bytes written",
+        p.phase.name(),
+        pct,
+        p.bytes_read,
+        p.bytes_written
+    )
+}
+
+// Hooks that keep a progress line on stderr, or nothing when stderr is not a
+// terminal. `clear_progress` wipes the line afterwards.
+fn progress_hooks<'a>() -> Hooks<'a> {
+    if !io::stderr().is_terminal() {
+        return Hooks::new();
+    }
+
+    let mut last_draw: Option<Instant> = None;
+    Hooks::new().with_progress(move |p| {
+        if last_draw.is_some_and(|t| t.elapsed() < PROGRESS_REFRESH) {
+            return;
+        }
+        last_draw = Some(Instant::now());
+        eprint!("\r\x1b[K{}", progress_line(p));
+    })
+}
+
+fn clear_progress() {
+    if io::stderr().is_terminal() {
+        eprint!("\r\x1b[K");
+    }
+}
+
+pub fn run_compress(args: &[String]) -> CliResult {
+    let args = parse_io_args(args, COMPRESS_USAGE, true)?;
+    if args.recursive {
+        return compress_tree(&args);
+    }
+    let output = args.output.unwrap_or_else(|| compressed_name(&args.input));
+
+    let content = read_input(&args.input)?;
+    let compressed = options::compress_with_hooks(&content, &args.options, &mut progress_hooks());
+    clear_progress();
+    let compressed = compressed?;
+    write_output(&output, &compressed, args.force)?;
+
+    if output != STDIO {
+        println!(
+            "{} -> {}: {} -> {} bytes ({:.1}%)",
+            args.input,
+            output,
+            content.len(),
+            compressed.len(),
+            percent(compressed.len(), content.len())
+        );
+    }
+
+    Ok(())
+}
+
+pub fn run_decompress(args: &[String]) -> CliResult {
+    let args = parse_io_args(args, DECOMPRESS_USAGE, false)?;
+    if args.recursive {
+        return decompress_tree(&args);
+    }
+    let output = match args.output.or_else(|| decompressed_name(&args.input)) {
+        Some(output) => output,
+        None => {
+            return Err(format!(
+                "{} does not end in {}, pick an output name with -o",
+                args.input, EXTENSION
+            )
+            .into());
+        }
+    };
+
+    let compressed = read_input(&args.input)?;
+    if Format::detect(&compressed) == Some(Format::Resync) {
+        return recover(&compressed, &output, args.force);
+    }
+    let content = options::decompress_with_hooks(&compressed, &mut progress_hooks());
+    clear_progress();
+    write_output(&output, &content?, args.force)
+}
+
+// Writes what survived of a damaged resync container, zeros standing in for
+// the lost ranges, and then fails naming them.
+fn recover(compressed: &[u8], output: &str, force: bool) -> CliResult {
+    let recovered = resync::decompress_resync(compressed)?;
+    write_output(output, &recovered.content, force)?;
+    if recovered.lost.is_empty() {
+        return Ok(());
+    }
+
+    let mut lost = 0;
+    for range in &recovered.lost {
+        eprintln!("lost bytes {}..{}", range.start, range.end);
+        lost += range.end - range.start;
+    }
+    Err(format!("{} of {} bytes lost", lost, recovered.content.len()).into())
+}
+
+fn symbol_name(symbol: u8) -> String {
+    if symbol.is_ascii_graphic() {
+        format!("'{}'", symbol as char)
+    } else {
+        format!("0x{:02x}", symbol)
+    }
+}
+
+pub fn run_inspect(args: &[String]) -> CliResult {
+    let input = single_input(args, INSPECT_USAGE)?;
+    let data = read_input(&input)?;
+    let mut out = io::stdout().lock();
+    match Format::detect(&data) {
+        Some(Format::Single) => {}
+        // only the single container has a table worth showing
+        Some(format) => {
+            let content = options::decompress(&data)?;
+            writeln!(out, "format:          {}", format.name())?;
+            writeln!(out, "original size:   {} bytes", content.len())?;
+            writeln!(
+                out,
+                "compressed size: {} bytes ({:.1}%)",
+                data.len(),
+                percent(data.len(), content.len())
+            )?;
+            return Ok(());
+        }
+        None => return Err("not a compressed file".into()),
+    }
+    let info = huffman_compress::read_container_info(&data)?;
+
+    let mut flags: Vec<&str> = vec![];
+    if info.flags & FLAG_STORED != 0 {
+        flags.push("stored");
+    }
+    if info.flags & FLAG_CHECKSUM != 0 {
+        flags.push("checksum");
+    }
+    if flags.is_empty() {
+        flags.push("none");
+    }
+    writeln!(out, "format:          HFC1 huffman container")?;
+    writeln!(out, "flags:           {}", flags.join(", "))?;
+    writeln!(out, "original size:   {} bytes", info.original_len)?;
+    writeln!(
+        out,
+        "compressed size: {} bytes ({:.1}%)",
+        data.len(),
+        percent(data.len(), info.original_len as usize)
+    )?;
+    writeln!(out, "header + table:  {} bytes", info.payload_offset)?;
+    if let Some(checksum) = info.checksum {
+        writeln!(out, "crc32:           {:08x}", checksum)?;
+    }
+
+    let content = huffman_compress::decompress(&data)?;
+    let frequency = huffman_compress::count_frequency(&content);
+    let total = content.len() as f64;
+    let entropy: f64 = frequency
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+    writeln!(
+        out,
+        "symbols:         {}",
+        frequency.iter().filter(|&&c| c > 0).count()
+    )?;
+    writeln!(out, "entropy:         {:.3} bits per byte", entropy)?;
+
+    let Some(lengths) = &info.code_lengths else {
+        return Ok(());
+    };
+
+    let coded_bits: u64 = frequency
+        .iter()
+        .zip(lengths)
+        .map(|(&count, &len)| count * len as u64)
+        .sum();
+    if !content.is_empty() {
+        writeln!(
+            out,
+            "average code:    {:.3} bits per byte",
+            coded_bits as f64 / total
+        )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "symbol  count       length  code")?;
+    let table = huffman_compress::packed_table_from_lengths(lengths);
+    for symbol in huffman_compress::canonical_ranks(lengths) {
+        let (code, len) = table[symbol];
+        writeln!(
+            out,
+            "{:<7} {:<11} {:<7} {:0width$b}",
+            symbol_name(symbol as u8),
+            frequency[symbol],
+            len,
+            code,
+            width = len as usize
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn run_verify(args: &[String]) -> CliResult {
+    let input = single_input(args, VERIFY_USAGE)?;
+    let data = read_input(&input)?;
+
+    // decompress checks the stored crc32 itself
+    let content = options::decompress(&data)?;
+    let checksum = match Format::detect(&data) {
+        Some(Format::Single) => huffman_compress::read_container_info(&data)?.checksum,
+        _ => None,
+    };
+    match checksum {
+        Some(checksum) => println!(
+            "{}: ok, {} bytes, crc32 {:08x}",
+            input,
+            content.len(),
+            checksum
+        ),
+        None => println!(
+            "{}: ok, {} bytes, crc32 {:08x} (no checksum stored, only decoded)",
+            input,
+            content.len(),
+            crc32(&content)
+        ),
+    }
+
+    Ok(())
+}
+
+// (entry path, file) for every input. A directory adds everything below it
+// under its own name, like `tar` does; our own outputs and the archive being
+// written are skipped.
+fn archive_inputs(
+    archive_path: &Path,
+    inputs: &[&str],
+    savings: &mut Savings,
+) -> Result<Vec<(String, PathBuf)>, Box<dyn Error>> {
+    let archive_canonical = fs::canonicalize(archive_path).ok();
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+        if !path.is_dir() {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                return Err(format!("cannot derive an entry name from {}", input).into());
+            };
+            files.push((name.to_string(), path.to_path_buf()));
+            continue;
+        }
+
+        let prefix = path.file_name().map(Path::new);
+        for relative in archive::walk_dir(path)? {
+            let src = path.join(&relative);
+            if is_compressed_name(&relative)
+                || (archive_canonical.is_some() && fs::canonicalize(&src).ok() == archive_canonical)
+            {
+                savings.skip(&src);
+                continue;
+            }
+
+            let name = prefix.map_or(relative.clone(), |p| p.join(&relative));
+            files.push((archive::entry_path(&name)?, src));
+        }
+    }
+
+    Ok(files)
+}
+
+pub fn run_archive(args: &[String]) -> CliResult {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["create", archive_path, inputs @ ..] if !inputs.is_empty() => {
+            let archive_path = Path::new(archive_path);
+            let mut savings = Savings::default();
+            let files = archive_inputs(archive_path, inputs, &mut savings)?;
+
+            let entries = archive::create_archive_named(archive_path, &files)?;
+            for entry in &entries {
+                println!(
+                    "added {} ({} -> {} bytes, {})",
+                    entry.path,
+                    entry.size,
+                    entry.compressed_size(),
+                    entry.codec.name()
+                );
+                savings.add(entry.size, entry.compressed_size());
+            }
+            return savings.report();
+        }
+        ["list", archive_path] => {
+            let archive = archive::Archive::open_file(Path::new(archive_path))?;
+            for entry in archive.entries() {
+                println!(
+                    "{:o}\t{}\t{}\t{}\t{:08x}\t{}\t{}",
+                    entry.mode,
+                    entry.size,
+                    entry.compressed_size(),
+                    entry.codec.name(),
+                    entry.crc32,
+                    entry.mtime,
+                    entry.path
+                );
+            }
+        }
+        ["extract", archive_path, entry, rest @ ..] if rest.len() <= 1 => {
+            let dest = match rest.first() {
+                Some(dest) => PathBuf::from(dest),
+                None => match Path::new(entry).file_name() {
+                    Some(name) => PathBuf::from(name),
+                    None => return Err(format!("cannot derive a file name from {}", entry).into()),
+                },
+            };
+            let mut archive = archive::Archive::open_file(Path::new(archive_path))?;
+            archive.extract_entry(entry, &dest)?;
+        }
+        ["extract-all", archive_path, rest @ ..] if rest.len() <= 1 => {
+            let dest_dir = PathBuf::from(rest.first().copied().unwrap_or("."));
+            let mut archive = archive::Archive::open_file(Path::new(archive_path))?;
+            for path in archive.extract_all(&dest_dir)? {
+                println!("extracted {}", path.display());
+            }
+        }
+        _ => return Err(UsageError(ARCHIVE_USAGE).into()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use learn::progress::Phase;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_io_args() {
+        assert_eq!(
+            parse_io_args(
+                &strings(&["in.txt", "-o", "out", "-f"]),
+                COMPRESS_USAGE,
+                true
+            )
+            .unwrap(),
+            IoArgs {
+                input: "in.txt".to_string(),
+                output: Some("out".to_string()),
+                force: true,
+                recursive: false,
+                options: CompressionOptions::default(),
+            }
+        );
+        assert_eq!(
+            parse_io_args(&strings(&["-", "-o", "-"]), COMPRESS_USAGE, true).unwrap(),
+            IoArgs {
+                input: "-".to_string(),
+                output: Some("-".to_string()),
+                force: false,
+                recursive: false,
+                options: CompressionOptions::default(),
+            }
+        );
+
+        let args = parse_io_args(
+            &strings(&["in", "-l", "2", "-b", "16", "-j", "3"]),
+            COMPRESS_USAGE,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            args.options,
+            CompressionOptions::builder()
+                .level(2)
+                .block_size(Some(16 * 1024))
+                .workers(3)
+                .build()
+        );
+        let args = parse_io_args(
+            &strings(&["in", "--level", "9", "-b", "0"]),
+            COMPRESS_USAGE,
+            true,
+        );
+        assert_eq!(args.unwrap().options.block_size, None);
+        assert!(parse_io_args(&strings(&["in", "-l", "5"]), DECOMPRESS_USAGE, false).is_err());
+        let args = parse_io_args(&strings(&["in", "--drift", "30"]), COMPRESS_USAGE, true).unwrap();
+        assert_eq!(args.options.rebuild_window, Some(adaptive::DEFAULT_WINDOW));
+        assert_eq!(args.options.rebuild, Rebuild::Drift(30));
+
+        for bad in [
+            &[][..],
+            &["-o", "out"][..],
+            &["in", "-o"][..],
+            &["a", "b"][..],
+            &["in", "--level"][..],
+            &["in", "-l", "0"][..],
+            &["in", "-l", "10"][..],
+            &["in", "-b", "x"][..],
+            &["in", "-j", "0"][..],
+            &["in", "-w", "0"][..],
+            &["-r", "-"][..],
+            &["-r", "dir", "-o", "-"][..],
+        ] {
+            assert!(parse_io_args(&strings(bad), COMPRESS_USAGE, true).is_err());
+        }
+    }
+
+    #[test]
+    fn test_output_names() {
+        assert_eq!(compressed_name("notes.txt"), "notes.txt.hfc");
+        assert_eq!(compressed_name("-"), "-");
+        assert_eq!(
+            decompressed_name("notes.txt.hfc"),
+            Some("notes.txt".to_string())
+        );
+        assert_eq!(decompressed_name("-"), Some("-".to_string()));
+        assert_eq!(decompressed_name("notes.txt"), None);
+        assert_eq!(decompressed_name(".hfc"), None);
+    }
+
+    #[test]
+    fn test_tree_round_trip() {
+        let dir = std::env::temp_dir().join(format!("learn_cli_tree_test_{}", std::process::id()));
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("logs/old")).unwrap();
+        fs::write(src.join("readme.txt"), "hello tree\n".repeat(50)).unwrap();
+        fs::write(src.join("logs/old/a.log"), "INFO ok\n".repeat(80)).unwrap();
+        fs::write(src.join("logs/empty"), b"").unwrap();
+        fs::write(src.join("logs/done.hfc"), b"not ours to touch").unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        fs::File::options()
+            .write(true)
+            .open(src.join("readme.txt"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let packed = dir.join("packed");
+        let restored = dir.join("restored");
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        run_compress(&[
+            "-r".to_string(),
+            path(&src),
+            "-o".to_string(),
+            path(&packed),
+        ])
+        .unwrap();
+        assert!(packed.join("logs/old/a.log.hfc").is_file());
+        assert!(!packed.join("logs/done.hfc.hfc").exists());
+
+        run_decompress(&[
+            "-r".to_string(),
+            path(&packed),
+            "-o".to_string(),
+            path(&restored),
+        ])
+        .unwrap();
+        for name in ["readme.txt", "logs/old/a.log", "logs/empty"] {
+            assert_eq!(
+                fs::read(restored.join(name)).unwrap(),
+                fs::read(src.join(name)).unwrap()
+            );
+        }
+        let restored_mtime = fs::metadata(restored.join("readme.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(restored_mtime, mtime);
+
+        // a second run refuses to replace what the first one wrote
+        assert!(
+            run_compress(&[
+                "-r".to_string(),
+                path(&src),
+                "-o".to_string(),
+                path(&packed),
+            ])
+            .is_err()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_progress_line() {
+        let p = Progress {
+            phase: Phase::Encoding,
+            bytes_read: 512,
+            bytes_written: 200,
+            total_bytes: 2048,
+        };
+        assert_eq!(
+            progress_line(&p),
+            "encoding 25%: 512 bytes read, 200 bytes written"
+        );
+    }
+
+    #[test]
+    fn test_write_output_keeps_existing_files() {
+        let dir = std::env::temp_dir().join(format!("learn_cli_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.hfc");
+        let path = path.to_str().unwrap();
+
+        write_output(path, b"first", false).unwrap();
+        assert!(write_output(path, b"second", false).is_err());
+        assert_eq!(std::fs::read(path).unwrap(), b"first");
+        write_output(path, b"second", true).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"second");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+use crate::huffman_compress::{self, HaffmanCompressedCode, HaffmanCompressedDict};
+
+// How much a dictionary built for one data set loses on another. Coding with
+// code lengths l gives the same size as an ideal coder for the distribution
+// q(s) = 2^-l(s), so the average code length is the cross-entropy of the data
+// against that distribution, and the gap to the data's own entropy is what
+// reusing the dictionary costs over a perfect model.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DictionaryCost {
+    // symbols in the frequency table
+    pub symbols: u64,
+    // size with the given dictionary, None when it has no code for `missing`
+    pub bits: Option<u64>,
+    // size with a huffman code built for the frequency table itself
+    pub optimal_bits: u64,
+    // the Shannon bound, sum of -log2 p over every symbol
+    pub entropy_bits: f64,
+    pub missing: Vec<u8>,
+}
+
+impl DictionaryCost {
+    // bits lost over the data's own huffman code; that one is length
+    // limited, so a dictionary with longer codes can come out ahead
+    pub fn overhead_bits(&self) -> Option<u64> {
+        self.bits.map(|bits| bits.saturating_sub(self.optimal_bits))
+    }
+
+    // in bits per symbol
+    pub fn cross_entropy(&self) -> Option<f64> {
+        self.bits.map(|bits| per_symbol(bits as f64, self.symbols))
+    }
+
+    pub fn entropy(&self) -> f64 {
+        per_symbol(self.entropy_bits, self.symbols)
+    }
+
+    // the Kullback-Leibler divergence, in bits per symbol
+    pub fn divergence(&self) -> Option<f64> {
+        self.cross_entropy().map(|h| h - self.entropy())
+    }
+}
+
+fn per_symbol(bits: f64, symbols: u64) -> f64 {
+    if symbols == 0 {
+        0.0
+    } else {
+        bits / symbols as f64
+    }
+}
+
+// cost of coding `frequency` with codes of the given `lengths`, 0 for none;
+// None when a count or a size does not fit in a u64
+pub fn code_lengths_cost(lengths: &[u8], frequency: &[u64]) -> Option<DictionaryCost> {
+    let symbols = frequency
+        .iter()
+        .try_fold(0u64, |total, &count| total.checked_add(count))?;
+    let optimal = huffman_compress::build_code_lengths(frequency);
+    let bits_with = |lengths: &[u8]| -> Option<u64> {
+        frequency
+            .iter()
+            .zip(lengths)
+            .try_fold(0u64, |bits, (&count, &len)| {
+                bits.checked_add(count.checked_mul(len as u64)?)
+            })
+    };
+
+    let missing: Vec<u8> = (0..frequency.len())
+        .filter(|&s| frequency[s] > 0 && lengths.get(s).is_none_or(|&len| len == 0))
+        .map(|s| s as u8)
+        .collect();
+    let entropy_bits = frequency
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| count as f64 * (symbols as f64 / count as f64).log2())
+        .sum();
+    let bits = if missing.is_empty() {
+        Some(bits_with(lengths)?)
+    } else {
+        None
+    };
+
+    Some(DictionaryCost {
+        symbols,
+        bits,
+        optimal_bits: bits_with(&optimal)?,
+        entropy_bits,
+        missing,
+    })
+}
+
+pub fn dictionary_cost(dic: &HaffmanCompressedDict, frequency: &[u64]) -> Option<DictionaryCost> {
+    code_lengths_cost(&huffman_compress::code_lengths_from_dic(dic), frequency)
+}
+
+// A symbol whose code differs between two dictionaries. None where one of
+// them has no code for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolDiff {
+    pub symbol: u8,
+    pub old: Option<HaffmanCompressedCode>,
+    pub new: Option<HaffmanCompressedCode>,
+}
+
+impl SymbolDiff {
+    // how many bits longer the new code is, when both have one
+    pub fn length_change(&self) -> Option<i32> {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => Some(new.len() as i32 - old.len() as i32),
+            _ => None,
+        }
+    }
+}
+
+// every symbol coded differently by `old` and `new`, ascending
+pub fn diff_dictionaries(
+    old: &HaffmanCompressedDict,
+    new: &HaffmanCompressedDict,
+) -> Vec<SymbolDiff> {
+    (0..=u8::MAX)
+        .filter_map(|symbol| {
+            let (old, new) = (old.get(&symbol), new.get(&symbol));
+            (old != new).then(|| SymbolDiff {
+                symbol,
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huffman_compress::{count_frequency, generate_canonical_dic};
+
+    const TRAINED: &[u8] = b"the quick brown fox jumps over the lazy dog, then sleeps";
+    const LATER: &[u8] = b"zzzz quick quiz, jazz buzz";
+
+    #[test]
+    fn test_own_code_costs_nothing_extra() {
+        let frequency = count_frequency(TRAINED);
+        let lengths = huffman_compress::build_code_lengths(&frequency);
+        let cost = code_lengths_cost(&lengths, &frequency).unwrap();
+
+        assert_eq!(cost.symbols, TRAINED.len() as u64);
+        assert_eq!(cost.overhead_bits(), Some(0));
+        // huffman is within a bit per symbol of the entropy
+        let h = cost.cross_entropy().unwrap();
+        assert!(h >= cost.entropy() && h < cost.entropy() + 1.0);
+        assert!(cost.missing.is_empty());
+        assert_eq!(
+            Some(cost),
+            dictionary_cost(&generate_canonical_dic(&lengths), &frequency)
+        );
+    }
+
+    #[test]
+    fn test_reused_dictionary() {
+        let trained = huffman_compress::build_code_lengths(&count_frequency(TRAINED));
+        let frequency = count_frequency(LATER);
+        let cost = code_lengths_cost(&trained, &frequency).unwrap();
+        assert!(cost.overhead_bits().unwrap() > 0);
+        assert!(cost.divergence().unwrap() > 0.0);
+        // the optimal code is what the HFC1 payload takes
+        let compressed = huffman_compress::compress(LATER);
+        assert!(compressed.len() as u64 * 8 >= cost.optimal_bits);
+
+        // 'u', 'i' and 'z' have codes, 'Q' does not
+        let cost = code_lengths_cost(&trained, &count_frequency(b"Quiz")).unwrap();
+        assert_eq!(cost.bits, None);
+        assert_eq!(cost.missing, vec![b'Q']);
+        assert_eq!(cost.cross_entropy(), None);
+
+        let empty = code_lengths_cost(&trained, &[0u64; 256]).unwrap();
+        assert_eq!(empty.bits, Some(0));
+        assert_eq!(empty.entropy(), 0.0);
+    }
+
+    #[test]
+    fn test_overflow_is_none() {
+        let mut lengths = vec![0u8; 256];
+        lengths[0] = 1;
+        lengths[1] = 1;
+        let mut frequency = vec![0u64; 256];
+        frequency[0] = u64::MAX;
+        frequency[1] = 1;
+        assert_eq!(code_lengths_cost(&lengths, &frequency), None);
+
+        // the count fits, three bits for each does not
+        frequency[0] = u64::MAX / 2;
+        frequency[1] = 0;
+        lengths[0] = 3;
+        assert_eq!(code_lengths_cost(&lengths, &frequency), None);
+    }
+
+    #[test]
+    fn test_diff_dictionaries() {
+        let mut old_lengths = vec![0u8; 256];
+        old_lengths[b'a' as usize] = 1;
+        old_lengths[b'b' as usize] = 2;
+        old_lengths[b'c' as usize] = 2;
+        let mut new_lengths = vec![0u8; 256];
+        new_lengths[b'a' as usize] = 1;
+        new_lengths[b'c' as usize] = 2;
+        new_lengths[b'd' as usize] = 2;
+        let old = generate_canonical_dic(&old_lengths);
+        let new = generate_canonical_dic(&new_lengths);
+
+        assert!(diff_dictionaries(&old, &old).is_empty());
+        let diff = diff_dictionaries(&old, &new);
+        let symbols: Vec<u8> = diff.iter().map(|d| d.symbol).collect();
+        // 'c' moves from 11 to 10
+        assert_eq!(symbols, vec![b'b', b'c', b'd']);
+        assert_eq!(diff[0].new, None);
+        assert_eq!(diff[1].length_change(), Some(0));
+        assert_eq!(diff[2].old, None);
+        assert_eq!(diff[2].length_change(), None);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::bitio::{BitOrder, BitReader, BitWriter};
+use crate::error::{Error, Result};
+use crate::huffman_compress::{self, CanonicalDecoder, PackedCodeTable, take};
+
+// Order-1 context modeling: the code for a byte is chosen by the byte before
+// it (0 for the first one). Contexts that are seen too rarely to pay for a
+// table of their own share one fallback table.
+//
+// magic            4 bytes, "HFO1"
+// flags            1 byte, huffman_compress::FLAG_STORED or 0
+// original length  u64, little endian
+// then, unless stored:
+// context map      32 bytes, bit `c % 8` (msb first) of byte `c / 8` is set
+//                  when context `c` has its own table
+// fallback         1 byte, 1 when a fallback table follows
+// tables           the fallback table, then one table per set bit in the map
+// payload          codes, msb first, zero padded to a byte
+//
+// A table is the number of symbols minus one (u8), then either the symbols
+// themselves (fewer than 32 symbols) or a 32 byte presence bitmap, and then
+// the code lengths of the symbols in ascending order, two per byte, high
+// nibble first. Context tables are limited to 15 bit codes for that.
+
+const ORDER1_MAGIC: &[u8; 4] = b"HFO1";
+const ORDER1_HEADER_LEN: usize = 13;
+
+pub const MAX_CONTEXT_CODE_LENGTH: u8 = 15;
+
+// contexts seen fewer times than this always use the fallback table
+pub const MIN_CONTEXT_COUNT: u64 = 32;
+
+const SPARSE_TABLE_LIMIT: usize = 32;
+
+pub type ContextFrequency = Vec<[u64; 256]>;
+
+// frequency[prev][cur] is how often `cur` follows `prev`
+pub fn count_context_frequency(content: &[u8]) -> ContextFrequency {
+    let mut frequency: ContextFrequency = vec![[0u64; 256]; 256];
+
+    let mut prev = 0u8;
+    for &ch in content {
+        frequency[prev as usize][ch as usize] += 1;
+        prev = ch;
+    }
+
+    frequency
+}
+
+fn build_context_lengths(frequency: &[u64]) -> Vec<u8> {
+    let mut lengths = huffman_compress::build_code_lengths(frequency);
+    huffman_compress::limit_code_lengths(&mut lengths, MAX_CONTEXT_CODE_LENGTH);
+
+    lengths
+}
+
+// None when `lengths` has no code for some symbol in `frequency`
+fn payload_bits(frequency: &[u64], lengths: &[u8]) -> Option<u64> {
+    let mut bits = 0u64;
+    for (&count, &len) in frequency.iter().zip(lengths) {
+        if count > 0 && len == 0 {
+            return None;
+        }
+        bits += count * len as u64;
+    }
+
+    Some(bits)
+}
+
+fn table_size(lengths: &[u8]) -> usize {
+    let n = lengths.iter().filter(|&&l| l > 0).count();
+    let symbols = if n < SPARSE_TABLE_LIMIT { n } else { 32 };
+
+    1 + symbols + n.div_ceil(2)
+}
+
+fn write_table(out: &mut Vec<u8>, lengths: &[u8]) {
+    let present: Vec<usize> = (0..256).filter(|&s| lengths[s] > 0).collect();
+    out.push((present.len() - 1) as u8);
+
+    if present.len() < SPARSE_TABLE_LIMIT {
+        out.extend(present.iter().map(|&s| s as u8));
+    } else {
+        let mut bitmap = [0u8; 32];
+        for &s in &present {
+            bitmap[s / 8] |= 0x80 >> (s % 8);
+        }
+        out.extend_from_slice(&bitmap);
+    }
+
+    for pair in present.chunks(2) {
+        let high = lengths[pair[0]];
+        let low = pair.get(1).map_or(0, |&s| lengths[s]);
+        out.push((high << 4) | low);
+    }
+}
+
+fn read_table(data: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let n = take(data, pos, 1)?[0] as usize + 1;
+
+    let present: Vec<usize> = if n < SPARSE_TABLE_LIMIT {
+        let symbols = take(data, pos, n)?;
+        if symbols.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Error::Corrupt("context table symbols are not in order"));
+        }
+        symbols.iter().map(|&s| s as usize).collect()
+    } else {
+        let bitmap = take(data, pos, 32)?;
+        let present: Vec<usize> = (0..256)
+            .filter(|&s| bitmap[s / 8] & (0x80 >> (s % 8)) != 0)
+            .collect();
+        if present.len() != n {
+            return Err(Error::Corrupt(
+                "context table bitmap does not match its size",
+            ));
+        }
+        present
+    };
+
+    let nibbles = take(data, pos, n.div_ceil(2))?;
+    let mut lengths = vec![0u8; 256];
+    for (i, &s) in present.iter().enumerate() {
+        let byte = nibbles[i / 2];
+        let len = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+        if len == 0 {
+            return Err(Error::Corrupt("invalid code length"));
+        }
+        lengths[s] = len;
+    }
+
+    Ok(lengths)
+}
+
+// Which contexts get a table of their own, and the code lengths for each
+// context (shared ones point at the fallback lengths).
+struct ContextTables {
+    own: [bool; 256],
+    fallback: Option<Vec<u8>>,
+    lengths: Vec<Vec<u8>>,
+}
+
+fn fallback_frequency(frequency: &ContextFrequency, own: &[bool; 256]) -> Vec<u64> {
+    let mut res = vec![0u64; 256];
+    for (c, row) in frequency.iter().enumerate() {
+        if !own[c] {
+            for (total, &count) in res.iter_mut().zip(row.iter()) {
+                *total += count;
+            }
+        }
+    }
+
+    res
+}
+
+fn choose_tables(frequency: &ContextFrequency) -> ContextTables {
+    let mut own = [false; 256];
+    for (c, row) in frequency.iter().enumerate() {
+        own[c] = row.iter().sum::<u64>() >= MIN_CONTEXT_COUNT;
+    }
+
+    // A dense context still merges when the fallback codes it for less than
+    // its own table would cost, table included.
+    let fallback = build_context_lengths(&fallback_frequency(frequency, &own));
+    let mut own_lengths: Vec<Option<Vec<u8>>> = vec![None; 256];
+    for c in 0..256 {
+        if !own[c] {
+            continue;
+        }
+        let lengths = build_context_lengths(&frequency[c]);
+        let own_cost =
+            payload_bits(&frequency[c], &lengths).unwrap() + table_size(&lengths) as u64 * 8;
+        match payload_bits(&frequency[c], &fallback) {
+            Some(shared_cost) if shared_cost <= own_cost => own[c] = false,
+            _ => own_lengths[c] = Some(lengths),
+        }
+    }
+
+    let fallback_frequency = fallback_frequency(frequency, &own);
+    let fallback = if fallback_frequency.iter().any(|&f| f > 0) {
+        Some(build_context_lengths(&fallback_frequency))
+    } else {
+        None
+    };
+
+    let lengths = own_lengths
+        .into_iter()
+        .map(|l| {
+            l.or_else(|| fallback.clone())
+                .unwrap_or_else(|| vec![0u8; 256])
+        })
+        .collect();
+
+    ContextTables {
+        own,
+        fallback,
+        lengths,
+    }
+}
+
+pub fn compress_order1(content: &[u8]) -> Vec<u8> {
+    let frequency = count_context_frequency(content);
+    let tables = choose_tables(&frequency);
+
+    let mut res: Vec<u8> = Vec::new();
+    res.extend_from_slice(ORDER1_MAGIC);
+    res.push(0);
+    res.extend_from_slice(&(content.len() as u64).to_le_bytes());
+
+    let mut bitmap = [0u8; 32];
+    for c in 0..256 {
+        if tables.own[c] {
+            bitmap[c / 8] |= 0x80 >> (c % 8);
+        }
+    }
+    res.extend_from_slice(&bitmap);
+    res.push(tables.fallback.is_some() as u8);
+    if let Some(fallback) = &tables.fallback {
+        write_table(&mut res, fallback);
+    }
+    for c in 0..256 {
+        if tables.own[c] {
+            write_table(&mut res, &tables.lengths[c]);
+        }
+    }
+
+    let payload_bits: u64 = (0..256)
+        .map(|c| payload_bits(&frequency[c], &tables.lengths[c]).expect("every context is covered"))
+        .sum();
+    let tables_len = (res.len() - ORDER1_HEADER_LEN) as u64;
+    if tables_len + payload_bits.div_ceil(8) >= content.len() as u64 {
+        res.truncate(ORDER1_HEADER_LEN);
+        res[4] = huffman_compress::FLAG_STORED;
+        res.extend_from_slice(content);
+        return res;
+    }
+
+    let packed: Vec<PackedCodeTable> = tables
+        .lengths
+        .iter()
+        .map(|l| huffman_compress::packed_table_from_lengths(l))
+        .collect();
+
+    let mut writer = BitWriter::new(res, BitOrder::MsbFirst);
+    let mut prev = 0u8;
+    for &ch in content {
+        let (code, len) = packed[prev as usize][ch as usize];
+        writer
+            .write_bits(code as u64, len as u32)
+            .expect("writing to a Vec cannot fail");
+        prev = ch;
+    }
+
+    writer.finish().expect("writing to a Vec cannot fail")
+}
+
+pub fn decompress_order1(data: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    if take(data, &mut pos, 4)? != ORDER1_MAGIC {
+        return Err(Error::Corrupt("not an order-1 container"));
+    }
+    let flags = take(data, &mut pos, 1)?[0];
+    let original_len = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+
+    match flags {
+        0 => {}
+        huffman_compress::FLAG_STORED => {
+            if data.len() - pos != original_len as usize {
+                return Err(Error::Corrupt("stored content has the wrong length"));
+            }
+            return Ok(data[pos..].to_vec());
+        }
+        _ => return Err(Error::Corrupt("unknown order-1 container flags")),
+    }
+
+    let bitmap: [u8; 32] = take(data, &mut pos, 32)?.try_into().unwrap();
+    let fallback = match take(data, &mut pos, 1)?[0] {
+        0 => None,
+        1 => Some(CanonicalDecoder::from_lengths(&read_table(data, &mut pos)?)),
+        _ => return Err(Error::Corrupt("invalid fallback table marker")),
+    };
+
+    let mut decoders: Vec<Option<CanonicalDecoder>> = Vec::with_capacity(256);
+    for c in 0..256 {
+        if bitmap[c / 8] & (0x80 >> (c % 8)) != 0 {
+            decoders.push(Some(CanonicalDecoder::from_lengths(&read_table(
+                data, &mut pos,
+            )?)));
+        } else {
+            decoders.push(None);
+        }
+    }
+
+    let payload = &data[pos..];
+    if original_len > payload.len() as u64 * 8 {
+        return Err(Error::Corrupt("order-1 payload is truncated"));
+    }
+
+    let mut reader = BitReader::new(payload, BitOrder::MsbFirst);
+    let mut res: Vec<u8> = Vec::with_capacity(original_len as usize);
+    let mut prev = 0u8;
+    for _ in 0..original_len {
+        let Some(decoder) = decoders[prev as usize].as_ref().or(fallback.as_ref()) else {
+            return Err(Error::Corrupt("no table for context"));
+        };
+        let Ok(Some(ch)) = decoder.read_symbol(&mut reader) else {
+            return Err(Error::Corrupt("order-1 payload is truncated"));
+        };
+        res.push(ch);
+        prev = ch;
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_lines(lines: usize) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        for i in 0..lines {
+            let level = ["INFO", "WARN", "DEBUG"][i % 3];
+            res.extend_from_slice(
+                format!(
+                    "2026-10-19 12:{:02}:{:02} {} worker-{} request handled\n",
+                    i % 60,
+                    (i * 7) % 60,
+                    level,
+                    i % 4
+                )
+                .as_bytes(),
+            );
+        }
+        res
+    }
+
+    #[test]
+    fn test_count_context_frequency() {
+        let frequency = count_context_frequency(b"abab");
+        assert_eq!(frequency[0][b'a' as usize], 1);
+        assert_eq!(frequency[b'a' as usize][b'b' as usize], 2);
+        assert_eq!(frequency[b'b' as usize][b'a' as usize], 1);
+        assert_eq!(
+            frequency
+                .iter()
+                .map(|row| row.iter().sum::<u64>())
+                .sum::<u64>(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_table_encoding() {
+        let mut sparse = vec![0u8; 256];
+        sparse[b'a' as usize] = 1;
+        sparse[b'z' as usize] = 15;
+        sparse[b'q' as usize] = 2;
+
+        let dense: Vec<u8> = (0..256)
+            .map(|s| if s % 3 == 0 { 0 } else { (s % 15 + 1) as u8 })
+            .collect();
+
+        for lengths in [sparse, dense] {
+            let mut out: Vec<u8> = Vec::new();
+            write_table(&mut out, &lengths);
+            assert_eq!(out.len(), table_size(&lengths));
+
+            let mut pos = 0;
+            assert_eq!(read_table(&out, &mut pos).unwrap(), lengths);
+            assert_eq!(pos, out.len());
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabcabcabcabcabcabcabcabc".to_vec(),
+            log_lines(200),
+            (0..=255u8).cycle().take(5000).collect(),
+        ];
+
+        for input in inputs {
+            let compressed = compress_order1(&input);
+            assert_eq!(decompress_order1(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_beats_order0_on_logs() {
+        let input = log_lines(500);
+        let order0 = huffman_compress::compress(&input);
+        let order1 = compress_order1(&input);
+        assert!(
+            order1.len() < order0.len(),
+            "{} >= {}",
+            order1.len(),
+            order0.len()
+        );
+    }
+
+    #[test]
+    fn test_sparse_contexts_share_the_fallback() {
+        let mut input = b"xy".repeat(100);
+        // '#' is only seen once, it must not get a table
+        input.extend_from_slice(b"#x");
+
+        let tables = choose_tables(&count_context_frequency(&input));
+        assert!(!tables.own[b'#' as usize]);
+        assert!(tables.fallback.is_some());
+        assert_eq!(decompress_order1(&compress_order1(&input)).unwrap(), input);
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(decompress_order1(b"").is_err());
+        assert!(decompress_order1(b"HFC1\x00").is_err());
+
+        let mut compressed = compress_order1(&log_lines(50));
+        compressed.truncate(60);
+        assert!(decompress_order1(&compressed).is_err());
+    }
+}
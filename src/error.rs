@@ -0,0 +1,69 @@
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    // the input is not something we produced, or it has been damaged
+    Corrupt(&'static str),
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    EntryNotFound(String),
+    DuplicateEntry(String),
+    UnsafePath(String),
+    // stopped through a progress::CancelToken
+    Cancelled,
+    // a caller provided buffer is short, `needed` bytes would do
+    BufferTooSmall {
+        needed: usize,
+    },
+    // a valid request that this code path does not do
+    Unsupported(&'static str),
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Corrupt(reason) => write!(f, "corrupt data: {}", reason),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            Error::EntryNotFound(path) => write!(f, "no entry named {}", path),
+            Error::DuplicateEntry(path) => write!(f, "entry {} is already present", path),
+            Error::UnsafePath(path) => write!(f, "refusing to use unsafe path {}", path),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::BufferTooSmall { needed } => {
+                write!(f, "buffer too small, {} bytes needed", needed)
+            }
+            Error::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+        }
+    }
+}
+
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
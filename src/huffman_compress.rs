@@ -1,12 +1,26 @@
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::BinaryHeap;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
 use bitvec::prelude::*;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::collections::HashMap;
+use core::cmp::Ordering;
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::io::Read;
+#[cfg(feature = "std")]
+use std::io::{self, ErrorKind, Read};
+
+#[cfg(feature = "std")]
+use crate::bitio::BitReader;
+use crate::crc32::crc32;
+use crate::error::{Error, Result};
+use crate::progress::{Hooks, Phase, REPORT_INTERVAL};
 
 
 #[derive(Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HuffmanTreeNode {
     pub weight: u64,
     pub val: Vec<u8>,
@@ -16,8 +30,14 @@ pub struct HuffmanTreeNode {
 
 impl Ord for HuffmanTreeNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        // reserve
-        other.weight.cmp(&self.weight)
+        // Ties go to the node with fewer leaves, then the smaller `val`, so
+        // the tree no longer depends on how the heap orders equal weights and
+        // matches the two-queue builder below.
+        other
+            .weight
+            .cmp(&self.weight)
+            .then_with(|| other.val.len().cmp(&self.val.len()))
+            .then_with(|| other.val.cmp(&self.val))
     }
 }
 
@@ -33,33 +53,37 @@ pub type HaffmanCompressedCode = BitVec<u8, Msb0>;
 
 pub type CompressedContent = BitVec<u8, Msb0>;
 
-pub type HaffmanCompressedDict = HashMap<u8, HaffmanCompressedCode>;
+// ordered, so the type is the same with and without std
+pub type HaffmanCompressedDict = BTreeMap<u8, HaffmanCompressedCode>;
 
 pub fn generate_haffman_tree_nodes() -> Vec<HuffmanTreeNode> {
-    let mut res: Vec<HuffmanTreeNode> = Vec::new();
-
-    res.push(HuffmanTreeNode {
-        weight: 10,
-        val: vec![b'A'],
-        left: None,
-        right: None,
-    });
-
-    res.push(HuffmanTreeNode {
-        weight: 20,
-        val: vec![b'B'],
-        left: None,
-        right: None,
-    });
-
-    res.push(HuffmanTreeNode {
-        weight: 25,
-        val: vec![b'C'],
-        left: None,
-        right: None,
-    });
+    vec![
+        HuffmanTreeNode {
+            weight: 10,
+            val: vec![b'A'],
+            left: None,
+            right: None,
+        },
+        HuffmanTreeNode {
+            weight: 20,
+            val: vec![b'B'],
+            left: None,
+            right: None,
+        },
+        HuffmanTreeNode {
+            weight: 25,
+            val: vec![b'C'],
+            left: None,
+            right: None,
+        },
+    ]
+}
 
-    res
+// The root ends up with the sum of every leaf weight, so that sum has to fit;
+// tables built from frequencies go through `fit_weights` first.
+fn add_weights(a: u64, b: u64) -> u64 {
+    a.checked_add(b)
+        .expect("total weight overflows u64, normalize the frequencies first")
 }
 
 pub fn generate_haffman_tree(nodes: Vec<HuffmanTreeNode>) -> HuffmanTree {
@@ -70,21 +94,14 @@ pub fn generate_haffman_tree(nodes: Vec<HuffmanTreeNode>) -> HuffmanTree {
     }
 
     while res.len() > 1 {
-        let Some(n1) = res.pop() else {
-            eprintln!("Error: failed to pop first node");
-            break;
-        };
-
-        let Some(n2) = res.pop() else {
-            eprintln!("Error: failed to pop second node");
-            break;
-        };
+        let n1 = res.pop().expect("two nodes are left");
+        let n2 = res.pop().expect("two nodes are left");
 
         let mut new_val = n1.val.clone();
         new_val.extend_from_slice(&n2.val);
 
         res.push(HuffmanTreeNode {
-            weight: n1.weight + n2.weight,
+            weight: add_weights(n1.weight, n2.weight),
             val: new_val,
             left: Some(Box::new(n1)),
             right: Some(Box::new(n2)),
@@ -99,11 +116,11 @@ pub fn generate_haffman_dic(node_tree: &mut BinaryHeap<HuffmanTreeNode>) -> Haff
         panic!("no in haffman tree");
     };
 
-    generate_haffman_dic_internal(&Box::new(root), BitVec::new())
+    generate_haffman_dic_internal(&root, BitVec::new())
 }
 
 fn generate_haffman_dic_internal(
-    node: &Box<HuffmanTreeNode>,
+    node: &HuffmanTreeNode,
     mut current_compress_code: HaffmanCompressedCode,
 ) -> HaffmanCompressedDict {
     // println!(
@@ -112,7 +129,7 @@ fn generate_haffman_dic_internal(
     // );
 
     if node.val.len() == 1 {
-        let mut res: HaffmanCompressedDict = HashMap::new();
+        let mut res = HaffmanCompressedDict::new();
 
         if current_compress_code.is_empty() {
             current_compress_code.push(false);
@@ -153,19 +170,77 @@ fn generate_haffman_dic_internal(
 
     left_map.extend(right_map);
 
-    return left_map;
+    left_map
 }
 
-pub fn generate_haffman_tree_nodes_with_frequency(frequency: &Vec<u64>) -> Vec<HuffmanTreeNode> {
+// Code length of every leaf, keyed by the leaf's `val`. Leaves are told apart
+// by having no children rather than by a one byte `val`, so this also works
+// for trees over multi-byte symbols. Lengths past 255 saturate, callers cap
+// them with `limit_code_lengths` anyway.
+pub fn generate_haffman_leaf_lengths(node_tree: &mut HuffmanTree) -> BTreeMap<Vec<u8>, u8> {
+    let mut res: BTreeMap<Vec<u8>, u8> = BTreeMap::new();
+    let Some(root) = node_tree.pop() else {
+        return res;
+    };
+
+    // a tree with a single leaf still needs a one bit code
+    let mut stack: Vec<(&HuffmanTreeNode, usize)> = vec![(&root, 0)];
+    while let Some((node, depth)) = stack.pop() {
+        match (node.left.as_ref(), node.right.as_ref()) {
+            (Some(left), Some(right)) => {
+                stack.push((left, depth + 1));
+                stack.push((right, depth + 1));
+            }
+            _ => {
+                res.insert(node.val.clone(), depth.clamp(1, u8::MAX as usize) as u8);
+            }
+        }
+    }
+
+    res
+}
+
+// Code lengths for symbols spelled as byte strings, plus one escape symbol
+// after them: `frequency` has one more entry than `symbols`, the last being
+// the escape's. Symbols must be distinct and non-empty, the escape is the
+// leaf with an empty `val`.
+pub fn build_multibyte_code_lengths<S: AsRef<[u8]>>(symbols: &[S], frequency: &[u64]) -> Vec<u8> {
+    let frequency = fit_weights(frequency);
+    let escape = symbols.len();
+    let leaves: Vec<HuffmanTreeNode> = (0..=escape)
+        .filter(|&i| frequency[i] > 0)
+        .map(|i| HuffmanTreeNode {
+            weight: frequency[i],
+            val: symbols.get(i).map_or(Vec::new(), |s| s.as_ref().to_vec()),
+            left: None,
+            right: None,
+        })
+        .collect();
+
+    let mut tree = generate_haffman_tree(leaves);
+    let leaf_lengths = generate_haffman_leaf_lengths(&mut tree);
+
+    let mut lengths: Vec<u8> = symbols
+        .iter()
+        .map(|s| leaf_lengths.get(s.as_ref()).copied().unwrap_or(0))
+        .collect();
+    lengths.push(leaf_lengths.get([].as_slice()).copied().unwrap_or(0));
+    limit_code_lengths(&mut lengths, MAX_CODE_LENGTH);
+
+    lengths
+}
+
+pub fn generate_haffman_tree_nodes_with_frequency(frequency: &[u64]) -> Vec<HuffmanTreeNode> {
+    let frequency = fit_weights(frequency);
     let mut res: Vec<HuffmanTreeNode> = Vec::new();
 
-    for i in 0..frequency.len() {
-        if frequency[i] == 0 {
+    for (i, &weight) in frequency.iter().enumerate() {
+        if weight == 0 {
             continue;
         }
 
         res.push(HuffmanTreeNode {
-            weight: frequency[i],
+            weight,
             val: vec![i.try_into().expect("size of frequency must not be greater than 255")],
             left: None,
             right: None,
@@ -175,41 +250,1398 @@ pub fn generate_haffman_tree_nodes_with_frequency(frequency: &Vec<u64>) -> Vec<H
     res
 }
 
+pub fn count_frequency(content: &[u8]) -> Vec<u64> {
+    let mut frequency: Vec<u64> = vec![0u64; 256];
+    add_frequency(&mut frequency, content);
+
+    frequency
+}
+
+// bytes counted into the u32 tables before they are added to the totals; a
+// table sees a quarter of them plus a few, far from overflowing
+const COUNT_CHUNK: usize = 1 << 30;
+
+// Adds the byte counts of `content` to `frequency`. With one table a run of
+// the same byte makes every increment wait for the previous one to land, so
+// the bytes of each word go to four tables in turn and those are summed at
+// the end. `frequency` has one entry for every byte value.
+pub fn add_frequency(frequency: &mut [u64], content: &[u8]) {
+    assert_eq!(frequency.len(), 256, "a frequency table has 256 entries");
+    let mut tables = [[0u32; 256]; 4];
+    for chunk in content.chunks(COUNT_CHUNK) {
+        let mut words = chunk.chunks_exact(8);
+        for word in &mut words {
+            let word = u64::from_le_bytes(word.try_into().unwrap());
+            tables[0][(word & 0xFF) as usize] += 1;
+            tables[1][((word >> 8) & 0xFF) as usize] += 1;
+            tables[2][((word >> 16) & 0xFF) as usize] += 1;
+            tables[3][((word >> 24) & 0xFF) as usize] += 1;
+            tables[0][((word >> 32) & 0xFF) as usize] += 1;
+            tables[1][((word >> 40) & 0xFF) as usize] += 1;
+            tables[2][((word >> 48) & 0xFF) as usize] += 1;
+            tables[3][(word >> 56) as usize] += 1;
+        }
+        for &ch in words.remainder() {
+            tables[0][ch as usize] += 1;
+        }
+
+        for table in tables.iter_mut() {
+            for (total, count) in frequency.iter_mut().zip(table.iter()) {
+                *total += *count as u64;
+            }
+            table.fill(0);
+        }
+    }
+}
+
+// Scale `frequency` so it sums to exactly `target_total`, roughly in
+// proportion, without dropping any symbol: every non-zero count stays at
+// least 1. None when there are more present symbols than `target_total`. An
+// all-zero table stays all zero.
+pub fn normalize_frequency(frequency: &[u64], target_total: u64) -> Option<Vec<u64>> {
+    let present = frequency.iter().filter(|&&f| f > 0).count() as u64;
+    if present == 0 {
+        return Some(frequency.to_vec());
+    }
+    let spare = target_total.checked_sub(present)?;
+    // a u128 holds the sum of 2^64 counts and the product of any two u64s
+    let total: u128 = frequency.iter().map(|&f| f as u128).sum();
+
+    // every present symbol gets 1 up front and the rest is shared out in
+    // proportion, rounding down; what rounding lost goes to the largest
+    // remainders, ties to the lower symbol
+    let mut res = vec![0u64; frequency.len()];
+    let mut remainders: Vec<(u128, usize)> = Vec::with_capacity(present as usize);
+    let mut assigned = present;
+    for (i, &f) in frequency.iter().enumerate() {
+        if f == 0 {
+            continue;
+        }
+        let scaled = (spare as u128).checked_mul(f as u128)?;
+        let share = u64::try_from(scaled / total).ok()?;
+        res[i] = share.checked_add(1)?;
+        assigned = assigned.checked_add(share)?;
+        remainders.push((scaled % total, i));
+    }
+
+    let leftover = target_total.checked_sub(assigned)?;
+    remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for &(_, i) in remainders.iter().take(leftover as usize) {
+        res[i] = res[i].checked_add(1)?;
+    }
+
+    Some(res)
+}
+
+// `frequency` as it is when its total fits in a u64, which is what the tree
+// root needs, otherwise normalized down to that
+pub fn fit_weights(frequency: &[u64]) -> Cow<'_, [u64]> {
+    if frequency
+        .iter()
+        .try_fold(0u64, |total, &f| total.checked_add(f))
+        .is_some()
+    {
+        return Cow::Borrowed(frequency);
+    }
+
+    let fitted = normalize_frequency(frequency, u64::MAX).expect("fewer than 2^64 symbols");
+    Cow::Owned(fitted)
+}
+
+// same as the tree path, but an empty frequency table gives an empty dictionary
+// instead of panicking on an empty heap
+pub fn generate_haffman_dic_from_frequency(frequency: &[u64]) -> HaffmanCompressedDict {
+    let tree_nodes = generate_haffman_tree_nodes_with_frequency(frequency);
+    if tree_nodes.is_empty() {
+        return HaffmanCompressedDict::new();
+    }
+
+    let mut tree = generate_haffman_tree(tree_nodes);
+    generate_haffman_dic(&mut tree)
+}
+
+#[cfg(feature = "std")]
 pub fn generate_haffman_dic_from_file(file_path: &str) -> HaffmanCompressedDict {
     let mut file = File::open(file_path).expect("failed to open data.bin");
     let mut contents: Vec<u8> = Vec::new();
     file.read_to_end(&mut contents).expect("failed to read file contents");
 
-    let mut frequency: Vec<u64> = vec![0u64; 256];
-    for ch in contents {
-        frequency[ch as usize] += 1;
-    }
-
-    let tree_nodes = generate_haffman_tree_nodes_with_frequency(&frequency);
-    let mut tree = generate_haffman_tree(tree_nodes);
-    let dic = generate_haffman_dic(&mut tree);
+    let frequency = count_frequency(&contents);
     // let dic = generate_haffman_dic(&mut generate_haffman_tree(generate_haffman_tree_nodes_with_frequency(&frequency)));
 
-    return dic;
+    generate_haffman_dic_from_frequency(&frequency)
 }
 
+// Encoding goes through the packed table; the dictionary is only converted.
+// Dictionaries with codes too long for it take the old bit by bit path.
+pub fn generate_new_content(content: &[u8], dic: &HaffmanCompressedDict) -> CompressedContent {
+    let Some(table) = packed_table_from_dic(dic) else {
+        let mut res: CompressedContent = BitVec::new();
+        for ch in content {
+            let compressed_code = dic.get(ch).expect("unrecognized charactor");
+            res.extend_from_bitslice(compressed_code.as_bitslice());
+        }
+        return res;
+    };
+
+    let bits: usize = content
+        .iter()
+        .map(|&ch| table[ch as usize].1 as usize)
+        .sum();
+    let mut res = CompressedContent::from_vec(encode_payload(content, &table));
+    res.truncate(bits);
 
-pub fn generate_new_content_from_file(file_path: &str, dic: &HaffmanCompressedDict) ->  CompressedContent {
+    res
+}
 
+#[cfg(feature = "std")]
+pub fn generate_new_content_from_file(file_path: &str, dic: &HaffmanCompressedDict) -> CompressedContent {
     let mut file = File::open(file_path).expect("failed to open data.bin");
     let mut contents: Vec<u8> = Vec::new();
     file.read_to_end(&mut contents).expect("failed to read file contents");
 
-    let mut res: CompressedContent = BitVec::new();
-
     println!("length of original content is: {}", contents.len());
 
-    for ch in contents {
-        let compressed_code = dic.get(&ch).expect("unrecognized charactor");
+    generate_new_content(&contents, dic)
+}
+
+// ================ linear construction ================
+//
+// With the leaves sorted by weight, the merged nodes come out in weight order
+// as well, so two FIFO queues replace the heap and building is O(n). Equal
+// weights prefer the leaf queue, which is the same tie-break as the heap
+// ordering above, so both paths give every symbol the same code length.
+
+// Symbols with a non-zero frequency, by ascending (frequency, symbol). An LSD
+// radix sort over the bytes of the counts, skipping bytes that are the same
+// for every symbol, so it stays linear for 256 symbols.
+pub fn sort_symbols_by_frequency(frequency: &[u64]) -> Vec<u8> {
+    let mut symbols: Vec<u8> = (0..frequency.len())
+        .filter(|&i| frequency[i] > 0)
+        .map(|i| {
+            i.try_into()
+                .expect("size of frequency must not be greater than 255")
+        })
+        .collect();
+    let mut scratch: Vec<u8> = vec![0; symbols.len()];
+
+    for shift in (0..64).step_by(8) {
+        let digit = |s: u8| ((frequency[s as usize] >> shift) & 0xFF) as usize;
+        let mut starts = [0usize; 257];
+        for &s in &symbols {
+            starts[digit(s) + 1] += 1;
+        }
+        if starts.contains(&symbols.len()) {
+            continue;
+        }
+
+        for d in 0..256 {
+            starts[d + 1] += starts[d];
+        }
+        for &s in &symbols {
+            scratch[starts[digit(s)]] = s;
+            starts[digit(s)] += 1;
+        }
+        core::mem::swap(&mut symbols, &mut scratch);
+    }
+
+    symbols
+}
+
+// equal weights take the leaf
+fn take_smallest(
+    leaves: &mut VecDeque<HuffmanTreeNode>,
+    merged: &mut VecDeque<HuffmanTreeNode>,
+) -> Option<HuffmanTreeNode> {
+    match (leaves.front(), merged.front()) {
+        (Some(leaf), Some(node)) if node.weight < leaf.weight => merged.pop_front(),
+        (Some(_), _) => leaves.pop_front(),
+        (None, _) => merged.pop_front(),
+    }
+}
+
+// Same shape as `generate_haffman_tree`, for leaves already sorted by
+// ascending weight.
+pub fn generate_haffman_tree_from_sorted(nodes: Vec<HuffmanTreeNode>) -> HuffmanTree {
+    debug_assert!(nodes.windows(2).all(|w| w[0].weight <= w[1].weight));
+
+    let mut leaves: VecDeque<HuffmanTreeNode> = nodes.into();
+    let mut merged: VecDeque<HuffmanTreeNode> = VecDeque::new();
+
+    while leaves.len() + merged.len() > 1 {
+        let n1 = take_smallest(&mut leaves, &mut merged).expect("two nodes are left");
+        let n2 = take_smallest(&mut leaves, &mut merged).expect("two nodes are left");
+
+        let mut new_val = n1.val.clone();
+        new_val.extend_from_slice(&n2.val);
+
+        merged.push_back(HuffmanTreeNode {
+            weight: add_weights(n1.weight, n2.weight),
+            val: new_val,
+            left: Some(Box::new(n1)),
+            right: Some(Box::new(n2)),
+        });
+    }
+
+    leaves.into_iter().chain(merged).collect()
+}
+
+// Moffat and Katajainen, "In-place calculation of minimum-redundancy codes".
+// `weights` must be ascending; on return every entry holds the code length of
+// the symbol that had that weight. The array is reused for the internal
+// nodes' weights, then their parent links, then their depths, so nothing is
+// allocated. Their sum has to fit in a u64.
+pub fn code_lengths_in_place(weights: &mut [u64]) {
+    let n = weights.len();
+    debug_assert!(weights.windows(2).all(|w| w[0] <= w[1]));
+    if n <= 1 {
+        weights.fill(1);
+        return;
+    }
+
+    // phase 1: weights[next] becomes the weight of internal node `next`, and
+    // an internal node taken as a child is overwritten with its parent index.
+    // Ties take the leaf, like the two-queue builder.
+    weights[0] = add_weights(weights[0], weights[1]);
+    let mut root = 0;
+    let mut leaf = 2;
+    for next in 1..n - 1 {
+        if leaf >= n || (root < next && weights[root] < weights[leaf]) {
+            weights[next] = weights[root];
+            weights[root] = next as u64;
+            root += 1;
+        } else {
+            weights[next] = weights[leaf];
+            leaf += 1;
+        }
+
+        if leaf >= n || (root < next && weights[root] < weights[leaf]) {
+            weights[next] = add_weights(weights[next], weights[root]);
+            weights[root] = next as u64;
+            root += 1;
+        } else {
+            weights[next] = add_weights(weights[next], weights[leaf]);
+            leaf += 1;
+        }
+    }
+
+    // phase 2: parent links become depths, the root being at depth 0
+    weights[n - 2] = 0;
+    for next in (0..n - 2).rev() {
+        weights[next] = weights[weights[next] as usize] + 1;
+    }
+
+    // phase 3: the number of internal nodes at each depth tells how many
+    // leaves sit one level below; the heaviest leaves get the short codes
+    let mut available = 1;
+    let mut used = 0;
+    let mut depth = 0;
+    let mut root = n as isize - 2;
+    let mut next = n as isize - 1;
+    while available > 0 {
+        while root >= 0 && weights[root as usize] == depth {
+            used += 1;
+            root -= 1;
+        }
+        while available > used {
+            weights[next as usize] = depth;
+            next -= 1;
+            available -= 1;
+        }
+        available = 2 * used;
+        depth += 1;
+        used = 0;
+    }
+}
+
+// code lengths without building a tree, identical to the heap path
+pub fn build_code_lengths_linear(frequency: &[u64]) -> Vec<u8> {
+    let frequency = fit_weights(frequency);
+    let symbols = sort_symbols_by_frequency(&frequency);
+    let mut weights: Vec<u64> = symbols.iter().map(|&s| frequency[s as usize]).collect();
+    code_lengths_in_place(&mut weights);
 
-        // res.extend_from_bitslice(compressed_code.as_bitslice());
-        res.extend(compressed_code.iter());
+    let mut lengths = vec![0u8; 256];
+    for (&s, &len) in symbols.iter().zip(&weights) {
+        lengths[s as usize] = len.min(u8::MAX as u64) as u8;
     }
 
-    return res;
+    lengths
+}
+
+// ================ canonical codes ================
+//
+// The tree above gives every symbol a code length, but the codes themselves
+// depend on how the heap broke ties. Re-assigning codes canonically (shorter
+// codes first, then by symbol) means only the lengths need to be stored.
+
+pub const MAX_CODE_LENGTH: u8 = 32;
+
+pub fn code_lengths_from_dic(dic: &HaffmanCompressedDict) -> Vec<u8> {
+    let mut lengths = vec![0u8; 256];
+    for (&symbol, code) in dic {
+        lengths[symbol as usize] = code.len().try_into().expect("code length must fit in u8");
+    }
+
+    lengths
+}
+
+// Clamp every length to `max_length`, then lengthen the longest codes that can
+// still grow until the lengths satisfy the Kraft inequality again. Only
+// pathological (fibonacci-like) frequency tables ever get here.
+pub fn limit_code_lengths(lengths: &mut [u8], max_length: u8) {
+    if lengths.iter().all(|&l| l <= max_length) {
+        return;
+    }
+
+    for l in lengths.iter_mut() {
+        if *l > max_length {
+            *l = max_length;
+        }
+    }
+
+    let capacity: u64 = 1 << max_length;
+    let mut kraft: u64 = lengths
+        .iter()
+        .filter(|&&l| l > 0)
+        .map(|&l| 1u64 << (max_length - l))
+        .sum();
+
+    while kraft > capacity {
+        let Some(longest) = (0..lengths.len())
+            .filter(|&i| lengths[i] > 0 && lengths[i] < max_length)
+            .max_by_key(|&i| lengths[i])
+        else {
+            panic!("too many symbols for code length {}", max_length);
+        };
+
+        lengths[longest] += 1;
+        kraft -= 1u64 << (max_length - lengths[longest]);
+    }
+}
+
+// Indices with a non-zero length, ordered the way canonical codes are
+// assigned. Works for any number of symbols, not just bytes.
+pub fn canonical_ranks(lengths: &[u8]) -> Vec<usize> {
+    let mut symbols: Vec<usize> = (0..lengths.len()).filter(|&i| lengths[i] > 0).collect();
+    symbols.sort_by_key(|&s| (lengths[s], s));
+
+    symbols
+}
+
+fn canonical_order(lengths: &[u8]) -> Vec<u8> {
+    canonical_ranks(lengths)
+        .into_iter()
+        .map(|s| s as u8)
+        .collect()
+}
+
+// canonical code of every index, right aligned; 0 where the length is 0
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut codes = vec![0u32; lengths.len()];
+    let mut code: u64 = 0;
+    let mut prev_len: u8 = 0;
+
+    for symbol in canonical_ranks(lengths) {
+        let len = lengths[symbol];
+        assert!(len <= MAX_CODE_LENGTH, "code length {} is too long", len);
+        code <<= len - prev_len;
+        codes[symbol] = code as u32;
+
+        code += 1;
+        prev_len = len;
+    }
+
+    codes
+}
+
+// (code, length) for every byte value, the code right aligned in the u32.
+// A length of 0 means the byte has no code.
+pub type PackedCodeTable = [(u32, u8); 256];
+
+pub fn packed_table_from_lengths(lengths: &[u8]) -> PackedCodeTable {
+    let mut table: PackedCodeTable = [(0, 0); 256];
+    for (i, code) in canonical_codes(lengths).into_iter().enumerate() {
+        table[i] = (code, lengths[i]);
+    }
+
+    table
+}
+
+// None when some code does not fit in a u32
+pub fn packed_table_from_dic(dic: &HaffmanCompressedDict) -> Option<PackedCodeTable> {
+    let mut table: PackedCodeTable = [(0, 0); 256];
+
+    for (&symbol, code) in dic {
+        if code.len() > 32 {
+            return None;
+        }
+        let value = code
+            .iter()
+            .by_vals()
+            .fold(0u32, |acc, bit| (acc << 1) | bit as u32);
+        table[symbol as usize] = (value, code.len() as u8);
+    }
+
+    Some(table)
+}
+
+pub fn dic_from_packed_table(table: &PackedCodeTable) -> HaffmanCompressedDict {
+    let mut res = HaffmanCompressedDict::new();
+
+    for (symbol, &(code, len)) in table.iter().enumerate() {
+        if len == 0 {
+            continue;
+        }
+        let mut bits: HaffmanCompressedCode = BitVec::with_capacity(len as usize);
+        for i in (0..len).rev() {
+            bits.push((code >> i) & 1 == 1);
+        }
+        res.insert(symbol as u8, bits);
+    }
+
+    res
+}
+
+pub fn generate_canonical_dic(lengths: &[u8]) -> HaffmanCompressedDict {
+    dic_from_packed_table(&packed_table_from_lengths(lengths))
+}
+
+// `content` coded with `table`, MSB first and padded with zero bits to a
+// whole byte
+pub fn encode_payload(content: &[u8], table: &PackedCodeTable) -> Vec<u8> {
+    let bits: usize = content
+        .iter()
+        .map(|&ch| table[ch as usize].1 as usize)
+        .sum();
+    let mut res = vec![0u8; bits.div_ceil(8)];
+    let mut encoder = SliceEncoder::new(0);
+    encoder.encode(content, table, &mut res);
+    encoder.finish(&mut res);
+
+    res
+}
+
+// Decodes canonical codes without building a tree: for each length we only
+// need how many codes have it, and the symbols in canonical order.
+#[derive(Clone)]
+pub struct CanonicalDecoder {
+    // counts[len] is the number of codes with that length, counts[0] is unused
+    counts: Vec<u16>,
+    symbols: Vec<u8>,
+}
+
+// how many codes there are of every length, indexed by length
+pub fn length_counts(lengths: &[u8]) -> Vec<u16> {
+    let max_len = lengths.iter().copied().max().unwrap_or(0);
+    let mut counts = vec![0u16; max_len as usize + 1];
+    for &l in lengths {
+        if l > 0 {
+            counts[l as usize] += 1;
+        }
+    }
+
+    counts
+}
+
+impl CanonicalDecoder {
+    pub fn from_lengths(lengths: &[u8]) -> Self {
+        CanonicalDecoder::from_counts(length_counts(lengths), canonical_order(lengths))
+    }
+
+    // `symbols` can be left empty by callers that only use `decode_index`
+    pub fn from_counts(counts: Vec<u16>, symbols: Vec<u8>) -> Self {
+        CanonicalDecoder { counts, symbols }
+    }
+
+    pub fn decode_symbol<I: Iterator<Item = bool>>(&self, bits: &mut I) -> Option<u8> {
+        Some(self.symbols[self.decode_index(bits)?])
+    }
+
+    // position of the next code in canonical order, see `canonical_ranks`
+    pub fn decode_index<I: Iterator<Item = bool>>(&self, bits: &mut I) -> Option<usize> {
+        // `code` is what we have read so far, `first` the first code of the
+        // current length and `index` where that length starts in `symbols`
+        let mut code: u64 = 0;
+        let mut first: u64 = 0;
+        let mut index: u64 = 0;
+
+        for &count in self.counts.iter().skip(1) {
+            code |= bits.next()? as u64;
+            let count = count as u64;
+            if code - first < count {
+                return Some((index + code - first) as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        None
+    }
+
+    #[cfg(feature = "std")]
+    pub fn read_symbol<R: Read>(&self, reader: &mut BitReader<R>) -> io::Result<Option<u8>> {
+        Ok(self.read_index(reader)?.map(|index| self.symbols[index]))
+    }
+
+    // `decode_index` from one peek at the longest code instead of bit by bit;
+    // None when the input ends inside the code
+    #[cfg(feature = "std")]
+    pub fn read_index<R: Read>(&self, reader: &mut BitReader<R>) -> io::Result<Option<usize>> {
+        let max_len = (self.counts.len() - 1) as u32;
+        let window = reader.peek_bits(max_len)?;
+        let mut first: u64 = 0;
+        let mut index: u64 = 0;
+
+        for (len, &count) in self.counts.iter().enumerate().skip(1) {
+            let code = window >> (max_len - len as u32);
+            let count = count as u64;
+            if code - first < count {
+                return match reader.consume(len as u32) {
+                    Ok(()) => Ok(Some((index + code - first) as usize)),
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e),
+                };
+            }
+            index += count;
+            first = (first + count) << 1;
+        }
+
+        Ok(None)
+    }
+}
+
+// ================ container ================
+//
+// magic            4 bytes, "HFC1"
+// flags            1 byte, see FLAG_*
+// original length  u64, little endian
+// checksum         u32 crc32 of the original content, only with FLAG_CHECKSUM
+// symbol count     u16, little endian
+// code lengths     (symbol: u8, length: u8) * symbol count
+// payload          canonical codes, msb first, zero padded to a byte
+//
+// With FLAG_STORED the code lengths are left out and the payload is the
+// original content as it is.
+
+const CONTAINER_MAGIC: &[u8; 4] = b"HFC1";
+const CONTAINER_HEADER_LEN: usize = 13;
+
+pub const FLAG_STORED: u8 = 0x01;
+pub const FLAG_CHECKSUM: u8 = 0x02;
+
+// code lengths for `frequency`, capped at MAX_CODE_LENGTH
+pub fn build_code_lengths(frequency: &[u64]) -> Vec<u8> {
+    let mut lengths = build_code_lengths_linear(frequency);
+    limit_code_lengths(&mut lengths, MAX_CODE_LENGTH);
+
+    lengths
+}
+
+// symbol count (u16) followed by (symbol, length) pairs
+pub fn write_code_lengths(out: &mut Vec<u8>, lengths: &[u8]) {
+    let symbols = canonical_order(lengths);
+    out.extend_from_slice(&(symbols.len() as u16).to_le_bytes());
+    for symbol in symbols {
+        out.push(symbol);
+        out.push(lengths[symbol as usize]);
+    }
+}
+
+pub fn read_code_lengths(data: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let symbol_count = u16::from_le_bytes(take(data, pos, 2)?.try_into().unwrap());
+
+    let mut lengths = vec![0u8; 256];
+    for pair in take(data, pos, symbol_count as usize * 2)?.chunks_exact(2) {
+        if pair[1] == 0 || pair[1] > MAX_CODE_LENGTH || lengths[pair[0] as usize] != 0 {
+            return Err(Error::Corrupt("invalid code length"));
+        }
+        lengths[pair[0] as usize] = pair[1];
+    }
+
+    Ok(lengths)
+}
+
+// decode exactly `count` symbols from the start of `payload`
+pub fn decode_payload(payload: &[u8], decoder: &CanonicalDecoder, count: u64) -> Result<Vec<u8>> {
+    decode_payload_with_hooks(payload, decoder, count, &mut Hooks::new())
+}
+
+pub fn decode_payload_with_hooks(
+    payload: &[u8],
+    decoder: &CanonicalDecoder,
+    count: u64,
+    hooks: &mut Hooks,
+) -> Result<Vec<u8>> {
+    // every symbol takes at least one bit
+    if count > payload.len() as u64 * 8 {
+        return Err(Error::Corrupt("huffman payload is truncated"));
+    }
+
+    let total_bits = payload.len() * 8;
+    let mut bits = payload.view_bits::<Msb0>().iter().by_vals();
+    let mut res: Vec<u8> = Vec::with_capacity(count as usize);
+    while (res.len() as u64) < count {
+        hooks.check()?;
+        let n = (count - res.len() as u64).min(REPORT_INTERVAL as u64);
+        for _ in 0..n {
+            let Some(symbol) = decoder.decode_symbol(&mut bits) else {
+                return Err(Error::Corrupt("huffman payload is truncated"));
+            };
+            res.push(symbol);
+        }
+        let bytes_read = (total_bits - bits.len()).div_ceil(8) as u64;
+        hooks.report(
+            Phase::Decoding,
+            bytes_read,
+            res.len() as u64,
+            payload.len() as u64,
+        );
+    }
+
+    Ok(res)
+}
+
+// exact size of the coded content in bits, without encoding it; None when
+// that does not fit in a u64
+pub fn estimate_payload_bits(frequency: &[u64], table: &PackedCodeTable) -> Option<u64> {
+    frequency
+        .iter()
+        .zip(table.iter())
+        .try_fold(0u64, |bits, (&count, &(_, len))| {
+            bits.checked_add(count.checked_mul(len as u64)?)
+        })
+}
+
+pub fn compress(content: &[u8]) -> Vec<u8> {
+    compress_container(content, false)
+}
+
+// same as `compress`, with a crc32 of `content` that `decompress` checks
+pub fn compress_with_checksum(content: &[u8]) -> Vec<u8> {
+    compress_container(content, true)
+}
+
+fn compress_container(content: &[u8], checksum: bool) -> Vec<u8> {
+    compress_with_hooks(content, checksum, &mut Hooks::new()).expect("nothing can cancel it")
+}
+
+// `compress` or `compress_with_checksum`, reporting to and checking `hooks`
+// every REPORT_INTERVAL bytes of input
+pub fn compress_with_hooks(content: &[u8], checksum: bool, hooks: &mut Hooks) -> Result<Vec<u8>> {
+    let total = content.len() as u64;
+
+    let mut frequency: Vec<u64> = vec![0u64; 256];
+    let mut read = 0u64;
+    for chunk in content.chunks(REPORT_INTERVAL) {
+        hooks.check()?;
+        add_frequency(&mut frequency, chunk);
+        read += chunk.len() as u64;
+        hooks.report(Phase::Counting, read, 0, total);
+    }
+
+    hooks.check()?;
+    let lengths = build_code_lengths(&frequency);
+    let table = packed_table_from_lengths(&lengths);
+    hooks.report(Phase::Building, total, 0, total);
+
+    let mut res: Vec<u8> = Vec::with_capacity(CONTAINER_HEADER_LEN + 6 + 512 + content.len());
+    res.extend_from_slice(CONTAINER_MAGIC);
+
+    let checksum_flag = if checksum { FLAG_CHECKSUM } else { 0 };
+    let write_header = |res: &mut Vec<u8>, flags: u8| {
+        res.push(flags);
+        res.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        if checksum {
+            res.extend_from_slice(&crc32(content).to_le_bytes());
+        }
+    };
+
+    // the code table has to pay for itself
+    let symbol_count = lengths.iter().filter(|&&l| l > 0).count();
+    let payload_size = estimate_payload_bits(&frequency, &table)
+        .expect("content in memory codes to fewer than u64::MAX bits")
+        .div_ceil(8) as usize;
+    let coded_size = 2 + symbol_count * 2 + payload_size;
+    if coded_size >= content.len() {
+        write_header(&mut res, FLAG_STORED | checksum_flag);
+        res.extend_from_slice(content);
+        hooks.report(Phase::Encoding, total, res.len() as u64, total);
+        return Ok(res);
+    }
+
+    write_header(&mut res, checksum_flag);
+    write_code_lengths(&mut res, &lengths);
+
+    let mut encoder = SliceEncoder::new(res.len());
+    res.resize(res.len() + payload_size, 0);
+    let mut read = 0u64;
+    for chunk in content.chunks(REPORT_INTERVAL) {
+        hooks.check()?;
+        encoder.encode(chunk, &table, &mut res);
+        read += chunk.len() as u64;
+        hooks.report(Phase::Encoding, read, encoder.pos as u64, total);
+    }
+    encoder.finish(&mut res);
+
+    Ok(res)
+}
+
+pub(crate) fn take<'a>(data: &'a [u8], pos: &mut usize, n: usize) -> Result<&'a [u8]> {
+    let Some(bytes) = data.get(*pos..pos.saturating_add(n)) else {
+        return Err(Error::Corrupt("huffman container is truncated"));
+    };
+    *pos += n;
+
+    Ok(bytes)
+}
+
+// Everything in front of the payload.
+pub struct ContainerInfo {
+    pub flags: u8,
+    pub original_len: u64,
+    pub checksum: Option<u32>,
+    // None for stored content
+    pub code_lengths: Option<Vec<u8>>,
+    // where the payload starts in the container
+    pub payload_offset: usize,
+}
+
+pub fn read_container_info(data: &[u8]) -> Result<ContainerInfo> {
+    let mut pos = 0;
+    if take(data, &mut pos, 4)? != CONTAINER_MAGIC {
+        return Err(Error::Corrupt("not a huffman container"));
+    }
+    let flags = take(data, &mut pos, 1)?[0];
+    let original_len = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+
+    if flags & !(FLAG_STORED | FLAG_CHECKSUM) != 0 {
+        return Err(Error::Corrupt("unknown huffman container flags"));
+    }
+    let checksum = match flags & FLAG_CHECKSUM {
+        0 => None,
+        _ => Some(u32::from_le_bytes(
+            take(data, &mut pos, 4)?.try_into().unwrap(),
+        )),
+    };
+    let code_lengths = match flags & FLAG_STORED {
+        0 => Some(read_code_lengths(data, &mut pos)?),
+        _ => None,
+    };
+
+    Ok(ContainerInfo {
+        flags,
+        original_len,
+        checksum,
+        code_lengths,
+        payload_offset: pos,
+    })
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    decompress_with_hooks(data, &mut Hooks::new())
+}
+
+pub fn decompress_with_hooks(data: &[u8], hooks: &mut Hooks) -> Result<Vec<u8>> {
+    let info = read_container_info(data)?;
+    let payload = &data[info.payload_offset..];
+
+    let content = match &info.code_lengths {
+        None => {
+            if payload.len() as u64 != info.original_len {
+                return Err(Error::Corrupt("stored content has the wrong length"));
+            }
+            payload.to_vec()
+        }
+        Some(lengths) => {
+            let decoder = CanonicalDecoder::from_lengths(lengths);
+            decode_payload_with_hooks(payload, &decoder, info.original_len, hooks)?
+        }
+    };
+
+    if let Some(expected) = info.checksum {
+        let actual = crc32(&content);
+        if actual != expected {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+    }
+
+    Ok(content)
+}
+
+// ================ caller provided buffers ================
+//
+// The same container, written to and read from slices the caller owns.
+// Everything that allocates (counting, code lengths, the decoder) happens
+// before the first byte of output, the coding loops only touch the slices. A
+// buffer that is too small is not written to, the error says what would fit.
+
+fn put(out: &mut [u8], pos: &mut usize, bytes: &[u8]) {
+    out[*pos..*pos + bytes.len()].copy_from_slice(bytes);
+    *pos += bytes.len();
+}
+
+// The one huffman packer, used by every coded payload. Codes are packed into
+// a 64-bit accumulator and stored 32 bits at a time; with codes of at most 32
+// bits the accumulator never holds more than 63. Writes into a slice known to
+// be large enough, starting at `pos`.
+// The input can come in pieces; `finish` zero pads the last byte.
+struct SliceEncoder {
+    acc: u64,
+    nbits: u32,
+    pos: usize,
+}
+
+impl SliceEncoder {
+    fn new(pos: usize) -> Self {
+        SliceEncoder {
+            acc: 0,
+            nbits: 0,
+            pos,
+        }
+    }
+
+    fn encode(&mut self, content: &[u8], table: &PackedCodeTable, out: &mut [u8]) {
+        for &ch in content {
+            let (code, len) = table[ch as usize];
+            assert!(len > 0, "unrecognized charactor");
+            self.acc = (self.acc << len) | code as u64;
+            self.nbits += len as u32;
+            if self.nbits >= 32 {
+                self.nbits -= 32;
+                put(
+                    out,
+                    &mut self.pos,
+                    &((self.acc >> self.nbits) as u32).to_be_bytes(),
+                );
+            }
+        }
+    }
+
+    // where the output ends
+    fn finish(mut self, out: &mut [u8]) -> usize {
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            put(out, &mut self.pos, &[(self.acc >> self.nbits) as u8]);
+        }
+        if self.nbits > 0 {
+            put(out, &mut self.pos, &[(self.acc << (8 - self.nbits)) as u8]);
+        }
+
+        self.pos
+    }
+}
+
+// Same bytes as `compress` or `compress_with_checksum`, written to the front
+// of `out`; returns how many.
+pub fn encode_into(content: &[u8], checksum: bool, out: &mut [u8]) -> Result<usize> {
+    let frequency = count_frequency(content);
+    let lengths = build_code_lengths(&frequency);
+    let table = packed_table_from_lengths(&lengths);
+    let symbols = canonical_order(&lengths);
+
+    let payload_size = estimate_payload_bits(&frequency, &table)
+        .expect("content in memory codes to fewer than u64::MAX bits")
+        .div_ceil(8) as usize;
+    let coded_size = 2 + symbols.len() * 2 + payload_size;
+    let stored = coded_size >= content.len();
+    let checksum_len = if checksum { 4 } else { 0 };
+    let needed =
+        CONTAINER_HEADER_LEN + checksum_len + if stored { content.len() } else { coded_size };
+    if out.len() < needed {
+        return Err(Error::BufferTooSmall { needed });
+    }
+
+    let mut flags = if checksum { FLAG_CHECKSUM } else { 0 };
+    if stored {
+        flags |= FLAG_STORED;
+    }
+    let mut pos = 0;
+    put(out, &mut pos, CONTAINER_MAGIC);
+    put(out, &mut pos, &[flags]);
+    put(out, &mut pos, &(content.len() as u64).to_le_bytes());
+    if checksum {
+        put(out, &mut pos, &crc32(content).to_le_bytes());
+    }
+    if stored {
+        put(out, &mut pos, content);
+        return Ok(pos);
+    }
+
+    put(out, &mut pos, &(symbols.len() as u16).to_le_bytes());
+    for &symbol in &symbols {
+        put(out, &mut pos, &[symbol, lengths[symbol as usize]]);
+    }
+    let mut encoder = SliceEncoder::new(pos);
+    encoder.encode(content, &table, out);
+    let pos = encoder.finish(out);
+    debug_assert_eq!(pos, needed);
+
+    Ok(pos)
+}
+
+// `decompress` into the front of `out`, returning the content length. On an
+// error other than BufferTooSmall `out` may hold part of the content.
+pub fn decode_into(data: &[u8], out: &mut [u8]) -> Result<usize> {
+    let info = read_container_info(data)?;
+    let payload = &data[info.payload_offset..];
+    let Ok(len) = usize::try_from(info.original_len) else {
+        return Err(Error::Corrupt("original length does not fit in memory"));
+    };
+    if out.len() < len {
+        return Err(Error::BufferTooSmall { needed: len });
+    }
+    let out = &mut out[..len];
+
+    match &info.code_lengths {
+        None => {
+            if payload.len() != len {
+                return Err(Error::Corrupt("stored content has the wrong length"));
+            }
+            out.copy_from_slice(payload);
+        }
+        Some(lengths) => {
+            // every symbol takes at least one bit
+            if len as u64 > payload.len() as u64 * 8 {
+                return Err(Error::Corrupt("huffman payload is truncated"));
+            }
+            let decoder = CanonicalDecoder::from_lengths(lengths);
+            let mut bits = payload.view_bits::<Msb0>().iter().by_vals();
+            for slot in out.iter_mut() {
+                let Some(symbol) = decoder.decode_symbol(&mut bits) else {
+                    return Err(Error::Corrupt("huffman payload is truncated"));
+                };
+                *slot = symbol;
+            }
+        }
+    }
+
+    if let Some(expected) = info.checksum {
+        let actual = crc32(out);
+        if actual != expected {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{CancelToken, Progress};
+
+    #[test]
+    fn test_canonical_dic_keeps_lengths() {
+        let frequency = count_frequency(b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh");
+        let dic = generate_haffman_dic_from_frequency(&frequency);
+        let lengths = code_lengths_from_dic(&dic);
+        let canonical = generate_canonical_dic(&lengths);
+
+        assert_eq!(code_lengths_from_dic(&canonical), lengths);
+        // no code is a prefix of another one
+        for (a, code_a) in &canonical {
+            for (b, code_b) in &canonical {
+                if a != b {
+                    assert!(!code_b.starts_with(code_a));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_frequency() {
+        let mut content = b"kkkkkkkkkkkkkkkkkkkkabcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh".repeat(37);
+        content.extend(0..=255u8);
+        for len in [0, 1, 7, 8, 9, 100, content.len()] {
+            let mut expected = vec![0u64; 256];
+            for &ch in &content[..len] {
+                expected[ch as usize] += 1;
+            }
+            assert_eq!(count_frequency(&content[..len]), expected);
+        }
+
+        let mut frequency = count_frequency(b"aab");
+        add_frequency(&mut frequency, b"bc");
+        assert_eq!(frequency[b'a' as usize..=b'c' as usize], [2, 2, 1]);
+    }
+
+    #[test]
+    fn test_normalize_frequency() {
+        let frequency = count_frequency(b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh");
+        for target in [11, 12, 100, 4096, 1 << 40, u64::MAX] {
+            let normalized = normalize_frequency(&frequency, target).unwrap();
+            assert_eq!(
+                normalized.iter().map(|&f| f as u128).sum::<u128>(),
+                target as u128
+            );
+            for (&f, &n) in frequency.iter().zip(&normalized) {
+                assert_eq!(f > 0, n > 0);
+            }
+        }
+        // 'k' is the most frequent byte and stays that way
+        let normalized = normalize_frequency(&frequency, 4096).unwrap();
+        assert_eq!(normalized.iter().max(), Some(&normalized[b'k' as usize]));
+
+        // 11 distinct bytes do not fit in 10
+        assert_eq!(normalize_frequency(&frequency, 10), None);
+        assert_eq!(normalize_frequency(&[0, 0], 5), Some(vec![0, 0]));
+        assert_eq!(normalize_frequency(&[1, 1, 2], 8), Some(vec![2, 2, 4]));
+    }
+
+    #[test]
+    fn test_weights_that_overflow_u64() {
+        let mut frequency = vec![0u64; 256];
+        frequency[b'a' as usize] = u64::MAX;
+        frequency[b'b' as usize] = u64::MAX / 2;
+        frequency[b'c' as usize] = u64::MAX / 2;
+        frequency[b'd' as usize] = 1;
+        assert!(
+            frequency
+                .iter()
+                .try_fold(0u64, |t, &f| t.checked_add(f))
+                .is_none()
+        );
+        assert!(
+            fit_weights(&frequency)
+                .iter()
+                .all(|&f| f <= u64::MAX / 2 + 1)
+        );
+
+        let lengths = build_code_lengths(&frequency);
+        assert_eq!(lengths[b'a' as usize], 1);
+        assert_eq!(lengths[b'd' as usize], 3);
+        let dic_lengths = code_lengths_from_dic(&generate_haffman_dic_from_frequency(&frequency));
+        assert_eq!(dic_lengths, lengths);
+        let multibyte = build_multibyte_code_lengths(&["ab", "cd"], &[u64::MAX, 3, u64::MAX]);
+        assert_eq!(multibyte.iter().filter(|&&l| l > 0).count(), 3);
+    }
+
+    #[test]
+    fn test_limit_code_lengths() {
+        // fibonacci weights give the deepest possible tree
+        let mut frequency = vec![0u64; 256];
+        let (mut a, mut b) = (1u64, 1u64);
+        for f in frequency.iter_mut().take(40) {
+            *f = a;
+            (a, b) = (b, a + b);
+        }
+        let mut lengths = code_lengths_from_dic(&generate_haffman_dic_from_frequency(&frequency));
+        assert!(lengths.iter().any(|&l| l > 16));
+
+        limit_code_lengths(&mut lengths, 16);
+        assert!(lengths.iter().all(|&l| l <= 16));
+        let kraft: u64 = lengths
+            .iter()
+            .filter(|&&l| l > 0)
+            .map(|&l| 1u64 << (16 - l))
+            .sum();
+        assert!(kraft <= 1 << 16);
+    }
+
+    #[test]
+    fn test_packed_table_matches_dic() {
+        let frequency = count_frequency(b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh");
+        let dic = generate_haffman_dic_from_frequency(&frequency);
+        let table = packed_table_from_dic(&dic).unwrap();
+        assert_eq!(dic_from_packed_table(&table), dic);
+
+        let lengths = code_lengths_from_dic(&dic);
+        assert_eq!(
+            dic_from_packed_table(&packed_table_from_lengths(&lengths)),
+            generate_canonical_dic(&lengths)
+        );
+    }
+
+    #[test]
+    fn test_leaf_lengths() {
+        let frequency = count_frequency(b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh");
+        let nodes = generate_haffman_tree_nodes_with_frequency(&frequency);
+        let mut tree = generate_haffman_tree(nodes);
+        let mut lengths = vec![0u8; 256];
+        for (val, len) in generate_haffman_leaf_lengths(&mut tree) {
+            lengths[val[0] as usize] = len;
+        }
+        assert_eq!(lengths, build_code_lengths(&frequency));
+
+        // multi-byte leaves, and a lone leaf still gets one bit
+        let mut tree = generate_haffman_tree(vec![HuffmanTreeNode {
+            weight: 3,
+            val: b"word".to_vec(),
+            left: None,
+            right: None,
+        }]);
+        let lengths = generate_haffman_leaf_lengths(&mut tree);
+        assert_eq!(lengths[b"word".as_slice()], 1);
+    }
+
+    #[test]
+    fn test_sort_symbols_by_frequency() {
+        let mut frequency = vec![0u64; 256];
+        frequency[b'a' as usize] = 300;
+        frequency[b'b' as usize] = 2;
+        frequency[b'c' as usize] = 1 << 40;
+        frequency[b'd' as usize] = 2;
+        frequency[b'e' as usize] = 44;
+        assert_eq!(sort_symbols_by_frequency(&frequency), b"bdeac".to_vec());
+        assert!(sort_symbols_by_frequency(&[0u64; 256]).is_empty());
+    }
+
+    #[test]
+    fn test_heap_tie_order() {
+        let leaf = |weight: u64, symbol: u8| HuffmanTreeNode {
+            weight,
+            val: vec![symbol],
+            left: None,
+            right: None,
+        };
+        // b and a merge first, smaller `val` on the left, then the merged
+        // node loses the tie with c for having more leaves
+        let mut tree = generate_haffman_tree(vec![leaf(2, b'c'), leaf(1, b'b'), leaf(1, b'a')]);
+        let root = tree.pop().unwrap();
+        assert_eq!(root.val, b"cab".to_vec());
+        let (left, right) = (root.left.unwrap(), root.right.unwrap());
+        assert_eq!(left.val, b"c".to_vec());
+        assert_eq!(right.val, b"ab".to_vec());
+        assert_eq!(right.left.unwrap().val, b"a".to_vec());
+        assert_eq!(right.right.unwrap().val, b"b".to_vec());
+    }
+
+    #[test]
+    fn test_linear_builders_match_heap() {
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        for round in 0..200 {
+            // few distinct weights, so ties are everywhere
+            let spread = [1, 2, 3, 5, 10, 1000][round % 6];
+            let symbols = 1 + round % 256;
+            let frequency: Vec<u64> = (0..256)
+                .map(|i| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    if i < symbols { state % (spread + 1) } else { 0 }
+                })
+                .collect();
+            if frequency.iter().all(|&f| f == 0) {
+                continue;
+            }
+
+            let heap = code_lengths_from_dic(&generate_haffman_dic_from_frequency(&frequency));
+
+            let nodes: Vec<HuffmanTreeNode> = sort_symbols_by_frequency(&frequency)
+                .into_iter()
+                .map(|s| HuffmanTreeNode {
+                    weight: frequency[s as usize],
+                    val: vec![s],
+                    left: None,
+                    right: None,
+                })
+                .collect();
+            let mut tree = generate_haffman_tree_from_sorted(nodes);
+            let two_queue = code_lengths_from_dic(&generate_haffman_dic(&mut tree));
+
+            assert_eq!(two_queue, heap);
+            assert_eq!(build_code_lengths_linear(&frequency), heap);
+        }
+    }
+
+    #[test]
+    fn test_code_lengths_in_place() {
+        let mut weights = vec![1, 1, 2, 4, 8];
+        code_lengths_in_place(&mut weights);
+        assert_eq!(weights, vec![4, 4, 3, 2, 1]);
+
+        let mut weights = vec![7];
+        code_lengths_in_place(&mut weights);
+        assert_eq!(weights, vec![1]);
+    }
+
+    #[test]
+    fn test_generate_new_content() {
+        let content = b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh";
+        let dic = generate_haffman_dic_from_frequency(&count_frequency(content));
+
+        // what the bit by bit version produced
+        let mut expected: CompressedContent = BitVec::new();
+        for ch in content {
+            expected.extend(dic[ch].iter());
+        }
+
+        assert_eq!(generate_new_content(content, &dic), expected);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_read_index_matches_decode_index() {
+        use crate::bitio::BitOrder;
+
+        let content = b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh";
+        let lengths = build_code_lengths(&count_frequency(content));
+        let decoder = CanonicalDecoder::from_lengths(&lengths);
+        let payload = encode_payload(content, &packed_table_from_lengths(&lengths));
+
+        // both stop at the same place when the input ends
+        for end in [payload.len(), 3] {
+            let mut bits = payload[..end].view_bits::<Msb0>().iter().by_vals();
+            let mut reader = BitReader::new(&payload[..end], BitOrder::MsbFirst);
+            while let Some(index) = decoder.decode_index(&mut bits) {
+                assert_eq!(decoder.read_index(&mut reader).unwrap(), Some(index));
+            }
+            assert_eq!(decoder.read_index(&mut reader).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"aaaaaaaa".to_vec(),
+            b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh".to_vec(),
+            (0..=255u8).cycle().take(3000).collect(),
+        ];
+
+        for input in inputs {
+            let compressed = compress(&input);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_stores_incompressible_content() {
+        let mut state: u32 = 1;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect();
+
+        for input in [random, b"abc".to_vec(), Vec::new()] {
+            let compressed = compress(&input);
+            assert_eq!(compressed[4], FLAG_STORED);
+            assert_eq!(compressed.len(), CONTAINER_HEADER_LEN + input.len());
+            assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+
+        let compressed = compress(&b"aaaabbbc".repeat(100));
+        assert_eq!(compressed[4], 0);
+        assert!(compressed.len() < 800);
+    }
+
+    #[test]
+    fn test_checksum() {
+        for input in [b"abc".to_vec(), b"aaaabbbc".repeat(100)] {
+            let compressed = compress_with_checksum(&input);
+            let info = read_container_info(&compressed).unwrap();
+            assert_eq!(info.checksum, Some(crc32(&input)));
+            assert_eq!(info.original_len, input.len() as u64);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+
+            // flip a bit of the checksum itself
+            let mut damaged = compressed.clone();
+            damaged[CONTAINER_HEADER_LEN] ^= 1;
+            assert!(matches!(
+                decompress(&damaged),
+                Err(Error::ChecksumMismatch { .. })
+            ));
+        }
+
+        let info = read_container_info(&compress(b"aaaabbbc")).unwrap();
+        assert_eq!(info.checksum, None);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let input = b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh".repeat(REPORT_INTERVAL / 32);
+
+        let mut seen: Vec<Progress> = Vec::new();
+        let mut hooks = Hooks::new().with_progress(|p| seen.push(*p));
+        let compressed = compress_with_hooks(&input, true, &mut hooks).unwrap();
+        drop(hooks);
+        assert_eq!(compressed, compress_with_checksum(&input));
+
+        // phases come in order and each one ends with the whole input read
+        let phases: Vec<Phase> = seen.iter().map(|p| p.phase).collect();
+        assert!(phases.is_sorted_by_key(|&p| p as u8));
+        assert!(phases.contains(&Phase::Building));
+        let last = seen.last().unwrap();
+        assert_eq!(last.phase, Phase::Encoding);
+        assert_eq!(last.bytes_read, input.len() as u64);
+        assert!(last.bytes_written <= compressed.len() as u64);
+
+        let mut decoded = 0;
+        let mut hooks = Hooks::new().with_progress(|p| decoded = p.bytes_written);
+        assert_eq!(
+            decompress_with_hooks(&compressed, &mut hooks).unwrap(),
+            input
+        );
+        drop(hooks);
+        assert_eq!(decoded, input.len() as u64);
+
+        let token = CancelToken::new();
+        token.cancel();
+        let mut hooks = Hooks::new().with_cancel(token);
+        assert!(matches!(
+            compress_with_hooks(&input, false, &mut hooks),
+            Err(Error::Cancelled)
+        ));
+        assert!(matches!(
+            decompress_with_hooks(&compressed, &mut hooks),
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_caller_provided_buffers() {
+        let text = b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh".repeat(50);
+        let noise: Vec<u8> = (0..=255u8).collect();
+        for content in [&text[..], &text[..1], &noise, b""] {
+            for checksum in [false, true] {
+                let expected = compress_container(content, checksum);
+                let mut out = vec![0xAAu8; expected.len() + 10];
+                assert!(matches!(
+                    encode_into(content, checksum, &mut out[..expected.len() - 1]),
+                    Err(Error::BufferTooSmall { needed }) if needed == expected.len()
+                ));
+                assert!(out.iter().all(|&b| b == 0xAA));
+                assert_eq!(
+                    encode_into(content, checksum, &mut out).unwrap(),
+                    expected.len()
+                );
+                assert_eq!(out[..expected.len()], expected[..]);
+
+                let mut decoded = vec![0u8; content.len() + 3];
+                assert_eq!(decode_into(&expected, &mut decoded).unwrap(), content.len());
+                assert_eq!(decoded[..content.len()], content[..]);
+            }
+        }
+
+        let compressed = compress_with_checksum(&text);
+        let mut short = vec![0u8; text.len() - 1];
+        assert!(matches!(
+            decode_into(&compressed, &mut short),
+            Err(Error::BufferTooSmall { needed }) if needed == text.len()
+        ));
+        // the stored crc32
+        let mut damaged = compressed.clone();
+        damaged[13] ^= 0x01;
+        let mut out = vec![0u8; text.len()];
+        assert!(matches!(
+            decode_into(&damaged, &mut out),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+        assert!(decode_into(&compressed[..compressed.len() / 2], &mut out).is_err());
+    }
+
+    #[test]
+    fn test_decompress_rejects_garbage() {
+        assert!(decompress(b"").is_err());
+        assert!(decompress(b"not a container").is_err());
+
+        let mut compressed = compress(b"hello huffman");
+        compressed.truncate(compressed.len() - 2);
+        assert!(decompress(&compressed).is_err());
+    }
 }
@@ -0,0 +1,510 @@
+use crate::bitio::{BitOrder, BitWriter};
+use crate::error::{Error, Result};
+use crate::huffman_compress::CanonicalDecoder;
+
+// The entropy layer of baseline JPEG (ITU T.81, annex C and F). A DHT segment
+// lists, per table, how many codes there are of each length from 1 to 16 and
+// then the symbols in code order, which is exactly what `CanonicalDecoder`
+// is built from. Scan data is msb first, and every 0xFF byte in it is
+// followed by a stuffed 0x00 so it cannot be taken for a marker.
+
+pub const MARKER_SOI: u8 = 0xD8;
+pub const MARKER_EOI: u8 = 0xD9;
+pub const MARKER_SOS: u8 = 0xDA;
+pub const MARKER_DHT: u8 = 0xC4;
+pub const MARKER_RST0: u8 = 0xD0;
+pub const MARKER_RST7: u8 = 0xD7;
+
+pub const MAX_JPEG_CODE_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableClass {
+    Dc = 0,
+    Ac = 1,
+}
+
+// Only built through `new`, so the counts always describe a code that exists
+// and match the symbols.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JpegHuffmanTable {
+    class: TableClass,
+    // destination the scans refer to it by, 0 to 3
+    id: u8,
+    // counts[i] codes of length i + 1
+    counts: [u8; MAX_JPEG_CODE_LENGTH],
+    // in code order
+    symbols: Vec<u8>,
+}
+
+impl JpegHuffmanTable {
+    pub fn new(
+        class: TableClass,
+        id: u8,
+        counts: [u8; MAX_JPEG_CODE_LENGTH],
+        symbols: Vec<u8>,
+    ) -> Result<Self> {
+        if id > 3 {
+            return Err(Error::Corrupt("huffman table destination out of range"));
+        }
+        let total: usize = counts.iter().map(|&c| c as usize).sum();
+        if total > 256 {
+            return Err(Error::Corrupt("huffman table has too many codes"));
+        }
+        // every length has to fit in what the shorter ones left over
+        let mut room: u32 = 1;
+        for &count in &counts {
+            room = (room << 1)
+                .checked_sub(count as u32)
+                .ok_or(Error::Corrupt("huffman table has too many codes"))?;
+        }
+        if symbols.len() != total {
+            return Err(Error::Corrupt(
+                "huffman table counts do not match its symbols",
+            ));
+        }
+
+        Ok(JpegHuffmanTable {
+            class,
+            id,
+            counts,
+            symbols,
+        })
+    }
+
+    pub fn class(&self) -> TableClass {
+        self.class
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn counts(&self) -> &[u8; MAX_JPEG_CODE_LENGTH] {
+        &self.counts
+    }
+
+    pub fn symbols(&self) -> &[u8] {
+        &self.symbols
+    }
+
+    // (symbol, code, length) in code order, the codes assigned as in annex C
+    pub fn codes(&self) -> Vec<(u8, u32, u8)> {
+        let mut res: Vec<(u8, u32, u8)> = Vec::with_capacity(self.symbols.len());
+        let mut symbols = self.symbols.iter();
+        let mut code: u32 = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            for _ in 0..count {
+                let &symbol = symbols.next().expect("counts match the symbols");
+                res.push((symbol, code, i as u8 + 1));
+                code += 1;
+            }
+            code <<= 1;
+        }
+
+        res
+    }
+
+    pub fn decoder(&self) -> CanonicalDecoder {
+        let mut counts: Vec<u16> = vec![0];
+        counts.extend(self.counts.iter().map(|&c| c as u16));
+        CanonicalDecoder::from_counts(counts, self.symbols.clone())
+    }
+
+    // symbol -> (code, length), length 0 for symbols without a code
+    pub fn encode_table(&self) -> [(u32, u8); 256] {
+        let mut table = [(0u32, 0u8); 256];
+        for (symbol, code, len) in self.codes() {
+            table[symbol as usize] = (code, len);
+        }
+        table
+    }
+
+    // the table as it appears in a DHT segment
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(((self.class as u8) << 4) | self.id);
+        out.extend_from_slice(&self.counts);
+        out.extend_from_slice(&self.symbols);
+    }
+}
+
+// The tables of one DHT segment, `segment` starting at its length field,
+// right after the marker
+pub fn parse_dht(segment: &[u8]) -> Result<Vec<JpegHuffmanTable>> {
+    let Some(len) = segment.get(..2) else {
+        return Err(Error::Corrupt("DHT segment is truncated"));
+    };
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    // the length counts its own two bytes
+    if len < 2 {
+        return Err(Error::Corrupt("DHT segment length is too small"));
+    }
+    let Some(mut data) = segment.get(2..len) else {
+        return Err(Error::Corrupt("DHT segment is truncated"));
+    };
+
+    let mut res: Vec<JpegHuffmanTable> = Vec::new();
+    while let Some((&info, rest)) = data.split_first() {
+        let class = match info >> 4 {
+            0 => TableClass::Dc,
+            1 => TableClass::Ac,
+            _ => return Err(Error::Corrupt("unknown huffman table class")),
+        };
+        let Some((counts, rest)) = rest.split_first_chunk::<MAX_JPEG_CODE_LENGTH>() else {
+            return Err(Error::Corrupt("DHT segment is truncated"));
+        };
+        let total: usize = counts.iter().map(|&c| c as usize).sum();
+        let Some((symbols, rest)) = rest.split_at_checked(total) else {
+            return Err(Error::Corrupt("DHT segment is truncated"));
+        };
+
+        res.push(JpegHuffmanTable::new(
+            class,
+            info & 0x0F,
+            *counts,
+            symbols.to_vec(),
+        )?);
+        data = rest;
+    }
+
+    Ok(res)
+}
+
+// Every table defined before the first scan of a JPEG file, later ones
+// replacing earlier ones with the same class and destination.
+pub fn read_tables(jpeg: &[u8]) -> Result<Vec<JpegHuffmanTable>> {
+    if jpeg.get(..2) != Some(&[0xFF, MARKER_SOI]) {
+        return Err(Error::Corrupt("not a JPEG file"));
+    }
+
+    let mut tables: Vec<JpegHuffmanTable> = Vec::new();
+    let mut pos = 2;
+    loop {
+        // any number of 0xFF fill bytes may come before a marker
+        while jpeg.get(pos..pos + 2) == Some(&[0xFF, 0xFF]) {
+            pos += 1;
+        }
+        let Some(&[0xFF, marker, len_hi, len_lo]) = jpeg.get(pos..pos + 4) else {
+            return Err(Error::Corrupt("JPEG file is truncated"));
+        };
+        if marker == MARKER_SOS || marker == MARKER_EOI {
+            return Ok(tables);
+        }
+
+        let len = u16::from_be_bytes([len_hi, len_lo]) as usize;
+        if len < 2 {
+            return Err(Error::Corrupt("JPEG segment length is too small"));
+        }
+        let Some(segment) = jpeg.get(pos + 2..pos + 2 + len) else {
+            return Err(Error::Corrupt("JPEG segment is truncated"));
+        };
+        if marker == MARKER_DHT {
+            for table in parse_dht(segment)? {
+                tables.retain(|t| (t.class, t.id) != (table.class, table.id));
+                tables.push(table);
+            }
+        }
+        pos += 2 + len;
+    }
+}
+
+// Bits of entropy coded data, with the stuffed zero bytes dropped. It stops
+// at the first marker, which `marker` then tells.
+pub struct JpegBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    left: u32,
+    marker: Option<u8>,
+}
+
+impl<'a> JpegBits<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        JpegBits {
+            data,
+            pos: 0,
+            byte: 0,
+            left: 0,
+            marker: None,
+        }
+    }
+
+    // the marker the data ran into, if it has
+    pub fn marker(&self) -> Option<u8> {
+        self.marker
+    }
+
+    // where the bytes not read yet start
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    // Throws away what is left of the current byte and steps over a restart
+    // marker, the way decoding resumes after every restart interval.
+    pub fn restart(&mut self) -> Result<()> {
+        self.left = 0;
+        if self.marker.is_none() && self.next_byte().is_some() {
+            return Err(Error::Corrupt("expected a restart marker"));
+        }
+        match self.marker.take() {
+            Some(MARKER_RST0..=MARKER_RST7) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(Error::Corrupt("expected a restart marker")),
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.marker.is_some() {
+            return None;
+        }
+        let &byte = self.data.get(self.pos)?;
+        if byte != 0xFF {
+            self.pos += 1;
+            return Some(byte);
+        }
+
+        match self.data.get(self.pos + 1) {
+            Some(0x00) => {
+                self.pos += 2;
+                Some(0xFF)
+            }
+            // fill bytes may come before a marker
+            Some(0xFF) => {
+                self.pos += 1;
+                self.next_byte()
+            }
+            Some(&marker) => {
+                self.marker = Some(marker);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // `n` bits as an unsigned number, n at most 16
+    pub fn read_bits(&mut self, n: u32) -> Option<u32> {
+        let mut value: u32 = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.next()? as u32;
+        }
+        Some(value)
+    }
+
+    // The RECEIVE and EXTEND steps of annex F: a coefficient of magnitude
+    // category `size` from its `size` extra bits.
+    pub fn receive_extend(&mut self, size: u32) -> Option<i32> {
+        if size == 0 {
+            return Some(0);
+        }
+        let value = self.read_bits(size)? as i32;
+        if value < 1 << (size - 1) {
+            Some(value - (1 << size) + 1)
+        } else {
+            Some(value)
+        }
+    }
+}
+
+impl Iterator for JpegBits<'_> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<bool> {
+        if self.left == 0 {
+            self.byte = self.next_byte()?;
+            self.left = 8;
+        }
+        self.left -= 1;
+        Some((self.byte >> self.left) & 1 == 1)
+    }
+}
+
+pub fn decode_symbol(decoder: &CanonicalDecoder, bits: &mut JpegBits) -> Result<u8> {
+    decoder
+        .decode_symbol(bits)
+        .ok_or(Error::Corrupt("JPEG scan data ends inside a code"))
+}
+
+// Codes for `symbols`, padded with 1 bits to a byte and stuffed, as a scan
+// would carry them
+pub fn encode(symbols: &[u8], table: &JpegHuffmanTable) -> Result<Vec<u8>> {
+    let codes = table.encode_table();
+    let mut writer = BitWriter::new(Vec::new(), BitOrder::MsbFirst);
+    for &symbol in symbols {
+        let (code, len) = codes[symbol as usize];
+        if len == 0 {
+            return Err(Error::Corrupt("symbol has no code in the JPEG table"));
+        }
+        writer
+            .write_bits(code as u64, len as u32)
+            .expect("writing to a Vec cannot fail");
+    }
+    while !writer.is_aligned() {
+        writer
+            .write_bit(true)
+            .expect("writing to a Vec cannot fail");
+    }
+    let bytes = writer.finish().expect("writing to a Vec cannot fail");
+
+    Ok(stuff(&bytes))
+}
+
+pub fn stuff(bytes: &[u8]) -> Vec<u8> {
+    let mut res: Vec<u8> = Vec::with_capacity(bytes.len() + bytes.len() / 64);
+    for &byte in bytes {
+        res.push(byte);
+        if byte == 0xFF {
+            res.push(0x00);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // table K.3, luminance DC
+    fn luminance_dc() -> JpegHuffmanTable {
+        JpegHuffmanTable::new(
+            TableClass::Dc,
+            0,
+            [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+            (0..12).collect(),
+        )
+        .unwrap()
+    }
+
+    fn some_ac() -> JpegHuffmanTable {
+        JpegHuffmanTable::new(
+            TableClass::Ac,
+            1,
+            [0, 2, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            vec![0x01, 0x00, 0x11, 0x21, 0x31, 0xF0],
+        )
+        .unwrap()
+    }
+
+    fn dht_segment(tables: &[JpegHuffmanTable]) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        for table in tables {
+            table.write(&mut body);
+        }
+        let mut res = ((body.len() + 2) as u16).to_be_bytes().to_vec();
+        res.extend_from_slice(&body);
+        res
+    }
+
+    #[test]
+    fn test_codes_match_the_standard() {
+        let codes = luminance_dc().codes();
+        let as_strings: Vec<String> = codes
+            .iter()
+            .map(|&(_, code, len)| format!("{:0width$b}", code, width = len as usize))
+            .collect();
+        assert_eq!(
+            as_strings,
+            vec![
+                "00",
+                "010",
+                "011",
+                "100",
+                "101",
+                "110",
+                "1110",
+                "11110",
+                "111110",
+                "1111110",
+                "11111110",
+                "111111110"
+            ]
+        );
+        assert_eq!(codes[5].0, 5);
+    }
+
+    #[test]
+    fn test_parse_dht() {
+        let segment = dht_segment(&[luminance_dc(), some_ac()]);
+        assert_eq!(
+            parse_dht(&segment).unwrap(),
+            vec![luminance_dc(), some_ac()]
+        );
+
+        assert!(parse_dht(&segment[..segment.len() - 1]).is_err());
+        let mut bad_class = segment.clone();
+        bad_class[2] = 0x20;
+        assert!(parse_dht(&bad_class).is_err());
+        // three codes of length 1 cannot exist
+        let mut overfull = segment.clone();
+        overfull[3] = 3;
+        assert!(parse_dht(&overfull).is_err());
+        // the length field counts itself, so 0 and 1 are impossible
+        assert!(parse_dht(&[0x00, 0x01]).is_err());
+        assert_eq!(parse_dht(&[0x00, 0x02]).unwrap(), vec![]);
+
+        let mut counts = [0u8; MAX_JPEG_CODE_LENGTH];
+        counts[1] = 2;
+        assert!(JpegHuffmanTable::new(TableClass::Dc, 0, counts, vec![1]).is_err());
+        assert!(JpegHuffmanTable::new(TableClass::Dc, 4, counts, vec![1, 2]).is_err());
+        assert!(JpegHuffmanTable::new(TableClass::Dc, 3, counts, vec![1, 2]).is_ok());
+    }
+
+    #[test]
+    fn test_decode_stuffed_stream() {
+        let table = luminance_dc();
+        // 11 has code 111111110, its first byte is all ones
+        let symbols = [11, 11, 0, 5, 11, 3, 7, 1];
+        let stream = encode(&symbols, &table).unwrap();
+        assert_eq!(&stream[..2], &[0xFF, 0x00]);
+
+        let mut data = stream.clone();
+        data.extend_from_slice(&[0xFF, MARKER_EOI]);
+        let decoder = table.decoder();
+        let mut bits = JpegBits::new(&data);
+        for &symbol in &symbols {
+            assert_eq!(decode_symbol(&decoder, &mut bits).unwrap(), symbol);
+        }
+        // the padding is all ones, then the marker stops the bits
+        while bits.next().is_some() {}
+        assert_eq!(bits.marker(), Some(MARKER_EOI));
+        assert_eq!(bits.position(), stream.len());
+    }
+
+    #[test]
+    fn test_restart_and_extend() {
+        let table = luminance_dc();
+        let decoder = table.decoder();
+        // category 3 with bits 010 is -5, category 2 with bits 11 is 3
+        let mut data = stuff(&[0b1000_1001, 0b1111_1111]);
+        data.extend_from_slice(&[0xFF, MARKER_RST0]);
+        data.extend(encode(&[0], &table).unwrap());
+
+        let mut bits = JpegBits::new(&data);
+        let size = decode_symbol(&decoder, &mut bits).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(bits.receive_extend(size as u32), Some(-5));
+        let size = decode_symbol(&decoder, &mut bits).unwrap();
+        assert_eq!(size, 2);
+        assert_eq!(bits.receive_extend(size as u32), Some(3));
+        // the padding left in the byte goes unread
+        bits.restart().unwrap();
+        assert_eq!(decode_symbol(&decoder, &mut bits).unwrap(), 0);
+        assert!(bits.restart().is_err());
+    }
+
+    #[test]
+    fn test_read_tables() {
+        let mut jpeg = vec![0xFF, MARKER_SOI];
+        // an APP0 segment to step over, then fill bytes
+        jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x04, 0xAB, 0xCD, 0xFF, 0xFF]);
+        for tables in [vec![luminance_dc()], vec![some_ac(), luminance_dc()]] {
+            jpeg.extend_from_slice(&[0xFF, MARKER_DHT]);
+            jpeg.extend(dht_segment(&tables));
+        }
+        jpeg.extend_from_slice(&[0xFF, MARKER_SOS, 0x00, 0x02]);
+
+        assert_eq!(read_tables(&jpeg).unwrap(), vec![some_ac(), luminance_dc()]);
+        assert!(read_tables(&jpeg[..20]).is_err());
+        assert!(read_tables(b"GIF89a").is_err());
+    }
+}
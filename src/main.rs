@@ -1,32 +1,58 @@
-mod bstree;
-mod huffman_compress;
-mod option_test;
-use bitvec::prelude::*;
-// use crate::huffman_compress::HuffmanTreeNode;
-// use std::collections::BinaryHeap;
-// use bstree::*;
-
-fn main() {
-    println!("Hello, world!");
-
-    // let mut heap = huffman_compress::generate_haffman_tree(huffman_compress::generate_haffman_tree_nodes());
-
-    // let haffman_map = huffman_compress::generate_haffman_dic(&mut heap);
-
-    // for (key, value) in &haffman_map {
-    //     println!("key: {}, value: {}", key, value);
-    // }
-
-
-    let dic = huffman_compress::generate_haffman_dic_from_file("data.bin");
-
-    for (key, value) in &dic {
-        println!("key: {}, value: {}", key, value);
+mod cli;
+
+use std::io;
+use std::process::ExitCode;
+
+use cli::UsageError;
+
+const USAGE: &str = "usage:
+    learn compress <in> [-o <out>] [-f] [-l <1-9>]
+    learn decompress <in> [-o <out>] [-f]
+    learn inspect <in>
+    learn verify <in>
+    learn archive <create|list|extract|extract-all> ...
+
+<in> and <out> can be - for stdin and stdout. compress writes <in>.hfc unless
+-o is given and decompress strips the .hfc again; -f replaces existing files.
+-l picks a level from 1 (fastest) to 9 (smallest), 6 being the default.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = args.first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    let rest = &args[1..];
+    let result = match command.as_str() {
+        "compress" => cli::run_compress(rest),
+        "decompress" => cli::run_decompress(rest),
+        "inspect" => cli::run_inspect(rest),
+        "verify" => cli::run_verify(rest),
+        "archive" => cli::run_archive(rest),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        _ => Err(UsageError(USAGE).into()),
+    };
+
+    // 0 on success, 1 when the work failed, 2 for bad arguments
+    let Err(e) = result else {
+        return ExitCode::SUCCESS;
+    };
+    if let Some(usage) = e.downcast_ref::<UsageError>() {
+        eprintln!("{}", usage);
+        return ExitCode::from(2);
     }
-
-    let new_content = huffman_compress::generate_new_content_from_file("data.bin", &dic);
-
-    println!("new content is: {}", &new_content);
-    println!("length of new content is: {}", new_content.len());
-
+    // whoever reads our output stopped early, as `learn inspect x | head` does
+    let io_error = match e.downcast_ref::<learn::Error>() {
+        Some(learn::Error::Io(e)) => Some(e),
+        _ => e.downcast_ref::<io::Error>(),
+    };
+    if io_error.is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) {
+        return ExitCode::SUCCESS;
+    }
+    eprintln!("error: {}", e);
+    ExitCode::FAILURE
 }
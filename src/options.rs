@@ -0,0 +1,451 @@
+use crate::adaptive::{self, Rebuild};
+use crate::block::{self, TableMode};
+use crate::context_model;
+use crate::error::{Error, Result};
+use crate::huffman_compress;
+use crate::parallel;
+use crate::progress::{Hooks, Phase};
+use crate::resync;
+use crate::token_model;
+use crate::utf8_model;
+
+// Numbered presets over the knobs the pipeline has, so callers can ask for
+// "fast" or "small" without knowing them. Lower levels cut the input into
+// blocks with cheap tables that code in parallel, higher ones spend time on
+// larger tables and context modeling. `decompress` reads whatever any level
+// wrote.
+//
+// level  model    layout
+// 1      bytes    1 MiB blocks, one shared table
+// 2      bytes    256 KiB blocks, one shared table
+// 3      bytes    64 KiB blocks, one shared table
+// 4      bytes    256 KiB blocks, a table each
+// 5      bytes    64 KiB blocks, a table each
+// 6      bytes    one container with a crc32, the default
+// 7      order-1  1 MiB blocks, a model each
+// 8      order-1  4 MiB blocks, a model each
+// 9      order-1  one container
+//
+// No level adds sync markers, they cost space and only help on transports
+// that damage data; ask for them with `sync_interval`. Table rebuilds, for
+// input whose statistics change along the way, are asked for the same way
+// with `rebuild_window`.
+
+pub const MIN_LEVEL: u8 = 1;
+pub const MAX_LEVEL: u8 = 9;
+pub const DEFAULT_LEVEL: u8 = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompressionOptions {
+    // the preset the rest started from
+    pub level: u8,
+    // code every byte by the one before it, see `context_model`
+    pub context_model: bool,
+    // cut the input into blocks of this size, or keep it in one container
+    pub block_size: Option<usize>,
+    // blocks share one table instead of carrying their own; only for the
+    // byte model
+    pub shared_table: bool,
+    // threads coding blocks at once
+    pub workers: usize,
+    // store a crc32 of the input; only the single byte model container has
+    // room for one
+    pub checksum: bool,
+    // write the byte model in segments of this many symbols behind sync
+    // markers, see `resync`; the layout settings above are then unused
+    pub sync_interval: Option<u32>,
+    // code the byte model as one stream whose table may be rebuilt every this
+    // many bytes, see `adaptive`; the layout settings above are then unused
+    pub rebuild_window: Option<usize>,
+    // when it is rebuilt
+    pub rebuild: Rebuild,
+}
+
+impl CompressionOptions {
+    pub fn new(level: u8) -> Self {
+        assert!(
+            (MIN_LEVEL..=MAX_LEVEL).contains(&level),
+            "compression level out of range"
+        );
+
+        let (context_model, block_size, shared_table) = match level {
+            1 => (false, Some(1 << 20), true),
+            2 => (false, Some(256 << 10), true),
+            3 => (false, Some(64 << 10), true),
+            4 => (false, Some(256 << 10), false),
+            5 => (false, Some(64 << 10), false),
+            6 => (false, None, false),
+            7 => (true, Some(1 << 20), false),
+            8 => (true, Some(4 << 20), false),
+            _ => (true, None, false),
+        };
+
+        CompressionOptions {
+            level,
+            context_model,
+            block_size,
+            shared_table,
+            workers: parallel::default_workers(),
+            checksum: true,
+            sync_interval: None,
+            rebuild_window: None,
+            rebuild: Rebuild::Always,
+        }
+    }
+
+    pub fn builder() -> CompressionOptionsBuilder {
+        CompressionOptionsBuilder::default()
+    }
+
+    fn table_mode(&self) -> TableMode {
+        if self.context_model {
+            TableMode::Order1
+        } else if self.shared_table {
+            TableMode::Shared
+        } else {
+            TableMode::PerBlock
+        }
+    }
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self::new(DEFAULT_LEVEL)
+    }
+}
+
+// A level plus overrides of single settings, applied on top of the level's
+// preset whatever order they were given in.
+#[derive(Clone, Debug, Default)]
+pub struct CompressionOptionsBuilder {
+    level: Option<u8>,
+    context_model: Option<bool>,
+    block_size: Option<Option<usize>>,
+    shared_table: Option<bool>,
+    workers: Option<usize>,
+    checksum: Option<bool>,
+    sync_interval: Option<Option<u32>>,
+    rebuild_window: Option<Option<usize>>,
+    rebuild: Option<Rebuild>,
+}
+
+impl CompressionOptionsBuilder {
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    pub fn context_model(mut self, context_model: bool) -> Self {
+        self.context_model = Some(context_model);
+        self
+    }
+
+    pub fn block_size(mut self, block_size: Option<usize>) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    pub fn shared_table(mut self, shared_table: bool) -> Self {
+        self.shared_table = Some(shared_table);
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn sync_interval(mut self, sync_interval: Option<u32>) -> Self {
+        self.sync_interval = Some(sync_interval);
+        self
+    }
+
+    pub fn rebuild_window(mut self, rebuild_window: Option<usize>) -> Self {
+        self.rebuild_window = Some(rebuild_window);
+        self
+    }
+
+    pub fn rebuild(mut self, rebuild: Rebuild) -> Self {
+        self.rebuild = Some(rebuild);
+        self
+    }
+
+    pub fn build(self) -> CompressionOptions {
+        let mut options = CompressionOptions::new(self.level.unwrap_or(DEFAULT_LEVEL));
+        if let Some(context_model) = self.context_model {
+            options.context_model = context_model;
+        }
+        if let Some(block_size) = self.block_size {
+            assert!(
+                block_size.is_none_or(|size| size > 0 && size <= u32::MAX as usize),
+                "invalid block size"
+            );
+            options.block_size = block_size;
+        }
+        if let Some(shared_table) = self.shared_table {
+            options.shared_table = shared_table;
+        }
+        if let Some(workers) = self.workers {
+            options.workers = workers.max(1);
+        }
+        if let Some(checksum) = self.checksum {
+            options.checksum = checksum;
+        }
+        if let Some(sync_interval) = self.sync_interval {
+            assert!(sync_interval != Some(0), "sync interval is zero");
+            options.sync_interval = sync_interval;
+        }
+        if let Some(rebuild_window) = self.rebuild_window {
+            assert!(
+                rebuild_window.is_none_or(|size| size > 0 && size <= u32::MAX as usize),
+                "invalid rebuild window"
+            );
+            options.rebuild_window = rebuild_window;
+        }
+        if let Some(rebuild) = self.rebuild {
+            options.rebuild = rebuild;
+        }
+
+        options
+    }
+}
+
+// The containers `decompress` understands, told apart by their magic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Single,
+    Order1,
+    Blocks,
+    Tokens,
+    Utf8,
+    Resync,
+    Adaptive,
+}
+
+impl Format {
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        match data.get(..4)? {
+            b"HFC1" => Some(Format::Single),
+            b"HFO1" => Some(Format::Order1),
+            b"HFB1" => Some(Format::Blocks),
+            b"HFT1" => Some(Format::Tokens),
+            b"HFU1" => Some(Format::Utf8),
+            b"HFR1" => Some(Format::Resync),
+            b"HFW1" => Some(Format::Adaptive),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Single => "HFC1 huffman container",
+            Format::Order1 => "HFO1 order-1 context model",
+            Format::Blocks => "HFB1 block file",
+            Format::Tokens => "HFT1 token container",
+            Format::Utf8 => "HFU1 utf-8 container",
+            Format::Resync => "HFR1 resync container",
+            Format::Adaptive => "HFW1 adaptive container",
+        }
+    }
+}
+
+pub fn compress(content: &[u8], options: &CompressionOptions) -> Vec<u8> {
+    compress_with_hooks(content, options, &mut Hooks::new())
+        .expect("nothing can cancel it, and no sync segment is over 4 GiB")
+}
+
+pub fn compress_with_hooks(
+    content: &[u8],
+    options: &CompressionOptions,
+    hooks: &mut Hooks,
+) -> Result<Vec<u8>> {
+    if let Some(interval) = options.sync_interval {
+        hooks.check()?;
+        let res = resync::compress_resync(content, interval)?;
+        let total = content.len() as u64;
+        hooks.report(Phase::Encoding, total, res.len() as u64, total);
+        return Ok(res);
+    }
+
+    if let Some(window) = options.rebuild_window {
+        hooks.check()?;
+        let res = adaptive::compress_adaptive(content, window, options.rebuild);
+        let total = content.len() as u64;
+        hooks.report(Phase::Encoding, total, res.len() as u64, total);
+        return Ok(res);
+    }
+
+    if let Some(block_size) = options.block_size {
+        let mut res: Vec<u8> = Vec::new();
+        parallel::compress_blocks_parallel(
+            content,
+            block_size,
+            options.table_mode(),
+            options.workers,
+            &mut res,
+            hooks,
+        )?;
+        return Ok(res);
+    }
+
+    if !options.context_model {
+        return huffman_compress::compress_with_hooks(content, options.checksum, hooks);
+    }
+
+    hooks.check()?;
+    let res = context_model::compress_order1(content);
+    let total = content.len() as u64;
+    hooks.report(Phase::Encoding, total, res.len() as u64, total);
+
+    Ok(res)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    decompress_with_hooks(data, &mut Hooks::new())
+}
+
+pub fn decompress_with_hooks(data: &[u8], hooks: &mut Hooks) -> Result<Vec<u8>> {
+    let Some(format) = Format::detect(data) else {
+        return Err(Error::Corrupt("unknown container format"));
+    };
+
+    let res = match format {
+        Format::Single => return huffman_compress::decompress_with_hooks(data, hooks),
+        Format::Blocks => {
+            let workers = parallel::default_workers();
+            return block::decompress_blocks_with_hooks(data, workers, hooks);
+        }
+        Format::Order1 => {
+            hooks.check()?;
+            context_model::decompress_order1(data)?
+        }
+        Format::Tokens => {
+            hooks.check()?;
+            token_model::decompress_tokens(data)?
+        }
+        Format::Utf8 => {
+            hooks.check()?;
+            utf8_model::decompress_utf8(data)?
+        }
+        Format::Adaptive => {
+            hooks.check()?;
+            adaptive::decompress_adaptive(data)?
+        }
+        // damage is only tolerated by callers of `resync` itself
+        Format::Resync => {
+            hooks.check()?;
+            let recovered = resync::decompress_resync(data)?;
+            if !recovered.lost.is_empty() {
+                return Err(Error::Corrupt("resync segments are damaged"));
+            }
+            recovered.content
+        }
+    };
+    let len = res.len() as u64;
+    hooks.report(Phase::Decoding, data.len() as u64, len, len);
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockReader;
+    use std::io::Cursor;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state: u32 = 7;
+        (0..len)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let spread = 4 + (i / 5000) % 12;
+                b'a' + ((state >> 16) as usize % spread) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_every_level_round_trips() {
+        let content = sample(300_000);
+        for level in MIN_LEVEL..=MAX_LEVEL {
+            let options = CompressionOptions::new(level);
+            let compressed = compress(&content, &options);
+            assert!(compressed.len() < content.len(), "level {}", level);
+            assert_eq!(decompress(&compressed).unwrap(), content, "level {}", level);
+        }
+
+        for level in [1, 6, 9] {
+            let compressed = compress(b"", &CompressionOptions::new(level));
+            assert!(decompress(&compressed).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_default_level_is_the_checksummed_container() {
+        let content = sample(10_000);
+        assert_eq!(
+            compress(&content, &CompressionOptions::default()),
+            huffman_compress::compress_with_checksum(&content)
+        );
+    }
+
+    #[test]
+    fn test_builder_overrides_the_preset() {
+        let options = CompressionOptions::builder()
+            .workers(0)
+            .block_size(Some(1000))
+            .level(9)
+            .build();
+        assert_eq!(options.level, 9);
+        assert!(options.context_model);
+        assert_eq!(options.block_size, Some(1000));
+        assert_eq!(options.workers, 1);
+        assert_eq!(
+            CompressionOptions::builder().build(),
+            CompressionOptions::default()
+        );
+
+        let content = sample(10_000);
+        let compressed = compress(&content, &options);
+        let reader = BlockReader::new(Cursor::new(&compressed)).unwrap();
+        assert_eq!(reader.table_mode(), TableMode::Order1);
+        assert_eq!(reader.block_count(), 10);
+        assert_eq!(decompress(&compressed).unwrap(), content);
+
+        let options = CompressionOptions::builder()
+            .sync_interval(Some(500))
+            .build();
+        let compressed = compress(&content, &options);
+        assert_eq!(Format::detect(&compressed), Some(Format::Resync));
+        assert_eq!(decompress(&compressed).unwrap(), content);
+
+        let options = CompressionOptions::builder()
+            .rebuild(Rebuild::Drift(5))
+            .rebuild_window(Some(2000))
+            .build();
+        let compressed = compress(&content, &options);
+        assert_eq!(Format::detect(&compressed), Some(Format::Adaptive));
+        assert_eq!(decompress(&compressed).unwrap(), content);
+    }
+
+    #[test]
+    fn test_decompress_detects_every_format() {
+        let content = sample(5000);
+        for compressed in [
+            token_model::compress_tokens(&content),
+            utf8_model::compress_utf8(&content),
+            context_model::compress_order1(&content),
+            resync::compress_resync(&content, 100).unwrap(),
+            adaptive::compress_adaptive(&content, 1000, Rebuild::Drift(10)),
+        ] {
+            assert_eq!(decompress(&compressed).unwrap(), content);
+        }
+        assert!(decompress(b"HFX1").is_err());
+        assert!(decompress(b"").is_err());
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::block::{self, BlockReader, TableMode};
+use crate::error::Result;
+use crate::huffman_compress;
+use crate::progress::{Hooks, Phase};
+
+// Blocks are independent, so they can be coded on any thread as long as they
+// are written back in order. Output is byte-identical to `compress_blocks` /
+// `decompress_blocks`.
+
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// inputs smaller than this per worker are not worth a thread
+const MIN_COUNT_SLICE: usize = 1 << 20;
+
+// `huffman_compress::count_frequency`, with the input cut into one slice per
+// worker and the tables added up
+pub fn count_frequency_parallel(content: &[u8], workers: usize) -> Vec<u64> {
+    let workers = workers.clamp(1, content.len() / MIN_COUNT_SLICE + 1);
+    if workers == 1 {
+        return huffman_compress::count_frequency(content);
+    }
+
+    let slice_len = content.len().div_ceil(workers);
+    thread::scope(|scope| {
+        let counts: Vec<_> = content
+            .chunks(slice_len)
+            .map(|slice| scope.spawn(|| huffman_compress::count_frequency(slice)))
+            .collect();
+
+        let mut frequency = vec![0u64; 256];
+        for count in counts {
+            let count = count.join().expect("counting cannot panic");
+            for (total, c) in frequency.iter_mut().zip(count) {
+                *total += c;
+            }
+        }
+        frequency
+    })
+}
+
+// Run `work` over `jobs` on `workers` threads and hand the results to `sink`
+// in job order. At most `max_in_flight` jobs are queued, being worked on, or
+// waiting for an earlier one to finish, which bounds the memory held. A job
+// that panics is not lost: the panic is passed on to the caller.
+fn map_ordered<J, O, F, S>(
+    workers: usize,
+    max_in_flight: usize,
+    jobs: impl Iterator<Item = Result<J>>,
+    work: F,
+    mut sink: S,
+) -> Result<()>
+where
+    J: Send,
+    O: Send,
+    F: Fn(J) -> Result<O> + Sync,
+    S: FnMut(O) -> Result<()>,
+{
+    let workers = workers.max(1);
+    let max_in_flight = max_in_flight.max(workers);
+
+    thread::scope(|scope| {
+        let (job_tx, job_rx) = mpsc::channel::<(usize, J)>();
+        let (result_tx, result_rx) = mpsc::channel::<(usize, thread::Result<Result<O>>)>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        for _ in 0..workers {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            let work = &work;
+            scope.spawn(move || {
+                loop {
+                    // the lock is only held while waiting for the next job
+                    let job = job_rx.lock().expect("worker panicked").recv();
+                    let Ok((i, job)) = job else {
+                        break;
+                    };
+                    // caught so the result channel still hears about job `i`
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| work(job)));
+                    if result_tx.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+
+        let mut jobs = jobs.enumerate();
+        let mut sent = 0;
+        let mut written = 0;
+        let mut pending: BTreeMap<usize, O> = BTreeMap::new();
+
+        loop {
+            while sent - written < max_in_flight {
+                let Some((i, job)) = jobs.next() else {
+                    break;
+                };
+                job_tx.send((i, job?)).expect("workers exited early");
+                sent += 1;
+            }
+
+            if written == sent {
+                break;
+            }
+
+            let (i, result) = result_rx.recv().expect("workers exited early");
+            // unwinding drops `job_tx`, so the workers are done before the
+            // scope passes the panic on
+            let result = result.unwrap_or_else(|payload| panic::resume_unwind(payload));
+            pending.insert(i, result?);
+            while let Some(out) = pending.remove(&written) {
+                sink(out)?;
+                written += 1;
+            }
+        }
+
+        // dropping `job_tx` lets the workers leave their loop
+        Ok(())
+    })
+}
+
+// `hooks` hears about every block written; cancelling stops handing out new
+// blocks and fails the call once the ones in flight are done.
+pub fn compress_blocks_parallel<W: Write>(
+    content: &[u8],
+    block_size: usize,
+    mode: TableMode,
+    workers: usize,
+    writer: &mut W,
+    hooks: &mut Hooks,
+) -> Result<u64> {
+    let total = content.len() as u64;
+    hooks.check()?;
+    let header = block::build_header(content, block_size, mode);
+    writer.write_all(&header.bytes)?;
+    hooks.report(Phase::Building, 0, header.bytes.len() as u64, total);
+
+    let cancel = hooks.cancel_token().cloned().unwrap_or_default();
+    let mut offsets: Vec<u64> = vec![header.bytes.len() as u64];
+    let mut read = 0u64;
+    map_ordered(
+        workers,
+        workers * 2,
+        content
+            .chunks(block_size)
+            .map(|chunk| cancel.check().map(|_| chunk)),
+        |chunk| {
+            cancel.check()?;
+            Ok((chunk.len(), header.encode_block(chunk)))
+        },
+        |(len, encoded)| {
+            writer.write_all(&encoded)?;
+            offsets.push(offsets.last().unwrap() + encoded.len() as u64);
+            read += len as u64;
+            hooks.report(Phase::Encoding, read, *offsets.last().unwrap(), total);
+            Ok(())
+        },
+    )?;
+
+    let mut index: Vec<u8> = Vec::new();
+    block::write_index(&mut index, content.len() as u64, &offsets);
+    writer.write_all(&index)?;
+
+    Ok(offsets.last().unwrap() + index.len() as u64)
+}
+
+// Decode every block of `reader` into `writer`, returns the bytes written.
+pub fn decompress_blocks_parallel<R: Read + Seek, W: Write>(
+    reader: &mut BlockReader<R>,
+    workers: usize,
+    writer: &mut W,
+    hooks: &mut Hooks,
+) -> Result<u64> {
+    let decoder = reader.decoder().clone();
+    let block_count = reader.block_count();
+    let original_len = reader.len();
+    let cancel = hooks.cancel_token().cloned().unwrap_or_default();
+    let mut read = 0u64;
+    let mut total = 0u64;
+
+    map_ordered(
+        workers,
+        workers * 2,
+        (0..block_count).map(|i| {
+            cancel.check()?;
+            reader.raw_block(i).map(|data| (i, data))
+        }),
+        |(i, data)| {
+            cancel.check()?;
+            Ok((data.len(), decoder.decode_block(i, &data)?))
+        },
+        |(len, block)| {
+            writer.write_all(&block)?;
+            read += len as u64;
+            total += block.len() as u64;
+            hooks.report(Phase::Decoding, read, total, original_len);
+            Ok(())
+        },
+    )?;
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{compress_blocks, decompress_blocks};
+    use std::io::Cursor;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        (0..len)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                // skewed so every block gets a different table
+                let spread = 4 + (i / 700) % 20;
+                b'a' + ((state >> 16) as usize % spread) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_output_matches_single_threaded() {
+        let content = sample(10_000);
+
+        for mode in [TableMode::PerBlock, TableMode::Shared, TableMode::Order1] {
+            let expected = compress_blocks(&content, 333, mode);
+            for workers in [1, 2, 3, 8] {
+                let mut out: Vec<u8> = Vec::new();
+                let written = compress_blocks_parallel(
+                    &content,
+                    333,
+                    mode,
+                    workers,
+                    &mut out,
+                    &mut Hooks::new(),
+                )
+                .unwrap();
+                assert_eq!(written, out.len() as u64);
+                assert_eq!(out, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_count_frequency_parallel() {
+        let content = sample(3 * MIN_COUNT_SLICE + 12_345);
+        let expected = huffman_compress::count_frequency(&content);
+        for workers in [0, 1, 2, 3, 8] {
+            assert_eq!(count_frequency_parallel(&content, workers), expected);
+        }
+        assert_eq!(
+            count_frequency_parallel(b"ab", 4),
+            huffman_compress::count_frequency(b"ab")
+        );
+    }
+
+    #[test]
+    fn test_decompress_round_trip() {
+        for len in [0, 1, 333, 10_000] {
+            let content = sample(len);
+            let compressed = compress_blocks(&content, 333, TableMode::PerBlock);
+
+            let mut reader = BlockReader::new(Cursor::new(compressed.clone())).unwrap();
+            let mut out: Vec<u8> = Vec::new();
+            let written =
+                decompress_blocks_parallel(&mut reader, 4, &mut out, &mut Hooks::new()).unwrap();
+            assert_eq!(written, len as u64);
+            assert_eq!(out, content);
+            assert_eq!(decompress_blocks(&compressed).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_decompress_reports_damage() {
+        let content = sample(10_000);
+        let mut compressed = compress_blocks(&content, 333, TableMode::PerBlock);
+        // the magic of the first block, right after the 9 byte header
+        compressed[9] ^= 0xFF;
+
+        let mut reader = BlockReader::new(Cursor::new(compressed)).unwrap();
+        let mut out: Vec<u8> = Vec::new();
+        assert!(decompress_blocks_parallel(&mut reader, 4, &mut out, &mut Hooks::new()).is_err());
+    }
+
+    #[test]
+    fn test_progress_and_cancel() {
+        use crate::error::Error;
+        use crate::progress::CancelToken;
+
+        let content = sample(10_000);
+        let mut out: Vec<u8> = Vec::new();
+        let mut last = None;
+        let mut hooks = Hooks::new().with_progress(|p| last = Some(*p));
+        let written =
+            compress_blocks_parallel(&content, 333, TableMode::Shared, 4, &mut out, &mut hooks)
+                .unwrap();
+        drop(hooks);
+        let last = last.unwrap();
+        assert_eq!(last.phase, Phase::Encoding);
+        assert_eq!(last.bytes_read, content.len() as u64);
+        // everything but the index
+        assert!(last.bytes_written < written);
+
+        let token = CancelToken::new();
+        let mut blocks = 0;
+        let stopper = token.clone();
+        let mut hooks = Hooks::new()
+            .with_progress(|p| {
+                if p.phase == Phase::Decoding {
+                    blocks += 1;
+                    stopper.cancel();
+                }
+            })
+            .with_cancel(token);
+        let mut reader = BlockReader::new(Cursor::new(out)).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        assert!(matches!(
+            decompress_blocks_parallel(&mut reader, 2, &mut decoded, &mut hooks),
+            Err(Error::Cancelled)
+        ));
+        drop(hooks);
+        assert!(blocks < reader.block_count());
+    }
+
+    #[test]
+    fn test_map_ordered_bounds_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let started = AtomicUsize::new(0);
+        let mut seen: Vec<usize> = Vec::new();
+
+        map_ordered(
+            4,
+            6,
+            (0..100).map(Ok),
+            |i| {
+                started.fetch_add(1, Ordering::SeqCst);
+                // later jobs finish first, so results have to wait for order
+                thread::sleep(std::time::Duration::from_micros((100 - i as u64) * 10));
+                Ok(i)
+            },
+            |i| {
+                assert!(started.load(Ordering::SeqCst) - seen.len() <= 6);
+                seen.push(i);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(seen, (0..100).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_map_ordered_passes_panics_on() {
+        let res = panic::catch_unwind(|| {
+            map_ordered(
+                4,
+                4,
+                (0..100).map(Ok),
+                |i| {
+                    assert!(i != 10, "job 10 fails");
+                    Ok(i)
+                },
+                |_| Ok(()),
+            )
+        });
+        let payload = res.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job 10 fails"));
+    }
+}
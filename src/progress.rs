@@ -0,0 +1,140 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::{Error, Result};
+
+// Progress callbacks and cooperative cancellation for the calls that can run
+// for a while. Both are optional and cost nothing when left out.
+
+// how much input goes between two reports or cancellation checks
+pub const REPORT_INTERVAL: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    // gathering symbol frequencies
+    Counting,
+    // turning them into code tables
+    Building,
+    Encoding,
+    Decoding,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Counting => "counting",
+            Phase::Building => "building",
+            Phase::Encoding => "encoding",
+            Phase::Decoding => "decoding",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    // of the input, within the current phase
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // input size, so `bytes_read` can be shown as a fraction
+    pub total_bytes: u64,
+}
+
+// Shared flag a caller sets to stop a running call at its next check, which
+// then fails with `Error::Cancelled`. Clones share the flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+#[derive(Default)]
+pub struct Hooks<'a> {
+    on_progress: Option<ProgressFn<'a>>,
+    cancel: Option<CancelToken>,
+}
+
+impl<'a> Hooks<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_progress(mut self, on_progress: impl FnMut(&Progress) + 'a) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn cancel_token(&self) -> Option<&CancelToken> {
+        self.cancel.as_ref()
+    }
+
+    pub fn check(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) => token.check(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn report(&mut self, phase: Phase, bytes_read: u64, bytes_written: u64, total_bytes: u64) {
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(&Progress {
+                phase,
+                bytes_read,
+                bytes_written,
+                total_bytes,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hooks() {
+        let token = CancelToken::new();
+        let mut seen: Vec<Progress> = Vec::new();
+        {
+            let mut hooks = Hooks::new()
+                .with_progress(|p| seen.push(*p))
+                .with_cancel(token.clone());
+            hooks.report(Phase::Counting, 1, 0, 2);
+            assert!(hooks.check().is_ok());
+
+            // a clone handed to someone else stops us
+            token.clone().cancel();
+            assert!(matches!(hooks.check(), Err(Error::Cancelled)));
+        }
+
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].phase, Phase::Counting);
+        assert!(Hooks::new().check().is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+use std::ops::Range;
+
+use crate::crc32::crc32;
+use crate::error::{Error, Result};
+use crate::huffman_compress::{self, CanonicalDecoder, take};
+
+// Byte coding for lossy transports. The payload is cut into segments of a
+// fixed number of symbols, each behind a sync marker and a small header of
+// its own, so a flipped bit only costs the segment it lands in: the decoder
+// notices the damage, looks for the next marker and carries on, reporting
+// which byte ranges it had to give up on.
+//
+// magic            4 bytes, "HFR1"
+// flags            1 byte, always 0
+// original length  u64, little endian
+// interval         u32, symbols per segment
+// code lengths     see `huffman_compress::write_code_lengths`
+// header crc32     u32 of everything above
+// segments         back to back, each one:
+//   marker         4 bytes, SYNC_MARKER
+//   index          u32, segment i holds the bytes from i * interval on
+//   symbol count   u32, interval except for the last segment
+//   payload length u32, in bytes
+//   crc32          u32 of the decoded bytes
+//   header crc32   u32 of the four fields above
+//   payload        codes, msb first, zero padded to a byte
+//
+// Everything but the segments has to arrive intact, there is nothing to fall
+// back on without the code table.
+
+const RESYNC_MAGIC: &[u8; 4] = b"HFR1";
+const SYNC_MARKER: &[u8; 4] = b"\xFFSYN";
+const SEGMENT_HEADER_LEN: usize = 24;
+
+pub const DEFAULT_SYNC_INTERVAL: u32 = 4096;
+
+// What could be decoded. Lost ranges are zero filled in `content`, which
+// always has the original length.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recovered {
+    pub content: Vec<u8>,
+    // ascending, adjacent ones merged
+    pub lost: Vec<Range<u64>>,
+}
+
+// Fails when a segment's payload does not fit its u32 length field, which
+// takes an interval of around a billion symbols.
+pub fn compress_resync(content: &[u8], interval: u32) -> Result<Vec<u8>> {
+    assert!(interval > 0, "sync interval is zero");
+
+    let lengths = huffman_compress::build_code_lengths(&huffman_compress::count_frequency(content));
+    let table = huffman_compress::packed_table_from_lengths(&lengths);
+
+    let mut res: Vec<u8> = Vec::new();
+    res.extend_from_slice(RESYNC_MAGIC);
+    res.push(0);
+    res.extend_from_slice(&(content.len() as u64).to_le_bytes());
+    res.extend_from_slice(&interval.to_le_bytes());
+    huffman_compress::write_code_lengths(&mut res, &lengths);
+    res.extend_from_slice(&crc32(&res).to_le_bytes());
+
+    for (i, segment) in content.chunks(interval as usize).enumerate() {
+        let payload = huffman_compress::encode_payload(segment, &table);
+
+        let mut header: Vec<u8> = Vec::with_capacity(SEGMENT_HEADER_LEN);
+        header.extend_from_slice(SYNC_MARKER);
+        let index = u32::try_from(i).expect("too many segments");
+        header.extend_from_slice(&index.to_le_bytes());
+        header.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        let Ok(payload_len) = u32::try_from(payload.len()) else {
+            return Err(Error::Unsupported("sync segment payload over 4 GiB"));
+        };
+        header.extend_from_slice(&payload_len.to_le_bytes());
+        header.extend_from_slice(&crc32(segment).to_le_bytes());
+        header.extend_from_slice(&crc32(&header[4..]).to_le_bytes());
+
+        res.extend_from_slice(&header);
+        res.extend_from_slice(&payload);
+    }
+
+    Ok(res)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+// The segment starting at `pos`, if there is an intact one: (index, decoded
+// bytes, where the next one starts)
+fn read_segment(
+    data: &[u8],
+    pos: usize,
+    decoder: &CanonicalDecoder,
+    expected_count: impl Fn(u32) -> Option<u32>,
+) -> Option<(u32, Vec<u8>, usize)> {
+    let header = data.get(pos..pos + SEGMENT_HEADER_LEN)?;
+    if &header[..4] != SYNC_MARKER || crc32(&header[4..20]) != read_u32(header, 20) {
+        return None;
+    }
+
+    let index = read_u32(header, 4);
+    let count = read_u32(header, 8);
+    if expected_count(index) != Some(count) {
+        return None;
+    }
+    let start = pos + SEGMENT_HEADER_LEN;
+    let end = start.checked_add(read_u32(header, 12) as usize)?;
+    let payload = data.get(start..end)?;
+
+    let segment = huffman_compress::decode_payload(payload, decoder, count as u64).ok()?;
+    if crc32(&segment) != read_u32(header, 16) {
+        return None;
+    }
+
+    Some((index, segment, end))
+}
+
+pub fn decompress_resync(data: &[u8]) -> Result<Recovered> {
+    let mut pos = 0;
+    if take(data, &mut pos, 4)? != RESYNC_MAGIC {
+        return Err(Error::Corrupt("not a resync container"));
+    }
+    if take(data, &mut pos, 1)?[0] != 0 {
+        return Err(Error::Corrupt("unknown resync container flags"));
+    }
+    let original_len = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+    let interval = u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap());
+    let lengths = huffman_compress::read_code_lengths(data, &mut pos)?;
+    let expected = crc32(&data[..pos]);
+    let actual = u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap());
+    if expected != actual {
+        return Err(Error::ChecksumMismatch { expected, actual });
+    }
+    if interval == 0 {
+        return Err(Error::Corrupt("sync interval is zero"));
+    }
+    // every byte takes at least one bit, so a length the segments could never
+    // hold is not believed before allocating for it
+    if original_len > (data.len() - pos) as u64 * 8 {
+        return Err(Error::Corrupt("segments cannot hold the original length"));
+    }
+    let segment_count = original_len.div_ceil(interval as u64);
+    if segment_count > u32::MAX as u64 {
+        return Err(Error::Corrupt("too many segments"));
+    }
+    let Ok(len) = usize::try_from(original_len) else {
+        return Err(Error::Corrupt("original length does not fit in memory"));
+    };
+
+    let decoder = CanonicalDecoder::from_lengths(&lengths);
+    let expected_count = |index: u32| {
+        let start = index as u64 * interval as u64;
+        (start < original_len).then(|| (original_len - start).min(interval as u64) as u32)
+    };
+
+    let mut content = vec![0u8; len];
+    let mut found = vec![false; segment_count as usize];
+    while pos < data.len() {
+        match read_segment(data, pos, &decoder, expected_count) {
+            Some((index, segment, next)) => {
+                let start = index as usize * interval as usize;
+                content[start..start + segment.len()].copy_from_slice(&segment);
+                found[index as usize] = true;
+                pos = next;
+            }
+            // skip to the next thing that looks like a marker
+            None => {
+                pos = data[pos + 1..]
+                    .windows(SYNC_MARKER.len())
+                    .position(|w| w == SYNC_MARKER)
+                    .map_or(data.len(), |offset| pos + 1 + offset);
+            }
+        }
+    }
+
+    let mut lost: Vec<Range<u64>> = Vec::new();
+    for (i, _) in found.iter().enumerate().filter(|&(_, &found)| !found) {
+        let start = i as u64 * interval as u64;
+        let end = (start + interval as u64).min(original_len);
+        match lost.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => lost.push(start..end),
+        }
+    }
+
+    Ok(Recovered { content, lost })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state: u32 = 99;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b"eeeeettaaoinshrdlu \n"[(state >> 16) as usize % 20]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for len in [0, 1, 99, 100, 101, 5000] {
+            let content = sample(len);
+            let compressed = compress_resync(&content, 100).unwrap();
+            let recovered = decompress_resync(&compressed).unwrap();
+            assert_eq!(recovered.content, content);
+            assert!(recovered.lost.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_flipped_bits_only_lose_their_segment() {
+        let content = sample(10_000);
+        let compressed = compress_resync(&content, 1000).unwrap();
+        // one bit in the payload of the third segment, one in the header of
+        // the seventh, and the marker of the eighth
+        let markers: Vec<usize> = compressed
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == SYNC_MARKER)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(markers.len(), 10);
+        let mut damaged = compressed.clone();
+        damaged[markers[2] + SEGMENT_HEADER_LEN + 50] ^= 0x10;
+        damaged[markers[6] + 9] ^= 0x01;
+        damaged[markers[7]] = 0;
+
+        let recovered = decompress_resync(&damaged).unwrap();
+        assert_eq!(recovered.lost, vec![2000..3000, 6000..8000]);
+        assert_eq!(recovered.content.len(), content.len());
+        assert_eq!(recovered.content[..2000], content[..2000]);
+        assert_eq!(recovered.content[3000..6000], content[3000..6000]);
+        assert_eq!(recovered.content[8000..], content[8000..]);
+        assert!(recovered.content[2000..3000].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_lost_bytes_and_truncation() {
+        let content = sample(10_000);
+        let compressed = compress_resync(&content, 1000).unwrap();
+
+        // a chunk of the transport gone missing entirely
+        let mut damaged = compressed.clone();
+        damaged.drain(compressed.len() / 2..compressed.len() / 2 + 700);
+        let recovered = decompress_resync(&damaged).unwrap();
+        assert!(!recovered.lost.is_empty());
+        // segments code to about 450 bytes, so 700 bytes touch three at most
+        let lost: u64 = recovered.lost.iter().map(|r| r.end - r.start).sum();
+        assert!(lost <= 3000);
+        assert_eq!(recovered.content[..3000], content[..3000]);
+        assert_eq!(recovered.content[7000..], content[7000..]);
+
+        let recovered = decompress_resync(&compressed[..compressed.len() - 10]).unwrap();
+        assert_eq!(recovered.lost, vec![9000..10_000]);
+    }
+
+    #[test]
+    fn test_damaged_header_is_an_error() {
+        let mut compressed = compress_resync(&sample(1000), 100).unwrap();
+        compressed[15] ^= 0x01;
+        assert!(decompress_resync(&compressed).is_err());
+        assert!(decompress_resync(b"HFR1").is_err());
+    }
+
+    #[test]
+    fn test_huge_original_length_is_refused() {
+        let lengths =
+            huffman_compress::build_code_lengths(&huffman_compress::count_frequency(b"ab"));
+        let mut header: Vec<u8> = Vec::new();
+        header.extend_from_slice(RESYNC_MAGIC);
+        header.push(0);
+        header.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        huffman_compress::write_code_lengths(&mut header, &lengths);
+        header.extend_from_slice(&crc32(&header).to_le_bytes());
+        header.extend_from_slice(SYNC_MARKER);
+
+        assert!(matches!(decompress_resync(&header), Err(Error::Corrupt(_))));
+    }
+}
@@ -0,0 +1,239 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+use crate::huffman_compress::{
+    self, HaffmanCompressedCode, HaffmanCompressedDict, MAX_CODE_LENGTH,
+};
+
+// Human readable forms of dictionaries and code length tables, for debugging
+// dumps and config files. Both come out as the same list of codes, ascending
+// by symbol:
+//
+//   [{"symbol": 97, "code": "010", "length": 3}, ...]
+//
+// A code length table only stores the lengths, so its codes are the canonical
+// ones and reading it back checks that every code is what its length implies.
+// Use the `dic` and `code_lengths` modules with `#[serde(with = "...")]`.
+// `HuffmanTreeNode` derives the traits directly, field by field.
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeEntry {
+    pub symbol: u8,
+    // '0' and '1', most significant bit first
+    pub code: String,
+    pub length: u8,
+}
+
+fn code_string(code: &HaffmanCompressedCode) -> String {
+    code.iter()
+        .by_vals()
+        .map(|bit| if bit { '1' } else { '0' })
+        .collect()
+}
+
+pub fn dic_to_entries(dic: &HaffmanCompressedDict) -> Vec<CodeEntry> {
+    let mut res: Vec<CodeEntry> = dic
+        .iter()
+        .map(|(&symbol, code)| CodeEntry {
+            symbol,
+            code: code_string(code),
+            length: code.len().try_into().expect("code length must fit in u8"),
+        })
+        .collect();
+    res.sort_by_key(|entry| entry.symbol);
+
+    res
+}
+
+pub fn dic_from_entries(entries: &[CodeEntry]) -> Result<HaffmanCompressedDict> {
+    let mut res = HaffmanCompressedDict::new();
+    for entry in entries {
+        if entry.code.is_empty() || entry.code.len() != entry.length as usize {
+            return Err(Error::Corrupt("code does not have the stated length"));
+        }
+        let mut code = HaffmanCompressedCode::with_capacity(entry.code.len());
+        for ch in entry.code.chars() {
+            match ch {
+                '0' => code.push(false),
+                '1' => code.push(true),
+                _ => return Err(Error::Corrupt("code is not a bit string")),
+            }
+        }
+        if res.insert(entry.symbol, code).is_some() {
+            return Err(Error::Corrupt("symbol has more than one code"));
+        }
+    }
+
+    // sorted, a code that is a prefix of another one is right before it
+    let mut codes: Vec<&str> = entries.iter().map(|entry| entry.code.as_str()).collect();
+    codes.sort_unstable();
+    if codes.windows(2).any(|w| w[1].starts_with(w[0])) {
+        return Err(Error::Corrupt("code is a prefix of another one"));
+    }
+
+    Ok(res)
+}
+
+pub fn lengths_to_entries(lengths: &[u8]) -> Vec<CodeEntry> {
+    let codes = huffman_compress::canonical_codes(lengths);
+    (0..lengths.len())
+        .filter(|&s| lengths[s] > 0)
+        .map(|s| CodeEntry {
+            symbol: s as u8,
+            code: format!("{:0width$b}", codes[s], width = lengths[s] as usize),
+            length: lengths[s],
+        })
+        .collect()
+}
+
+pub fn lengths_from_entries(entries: &[CodeEntry]) -> Result<Vec<u8>> {
+    let mut lengths = vec![0u8; 256];
+    for entry in entries {
+        if entry.length == 0 || entry.length > MAX_CODE_LENGTH {
+            return Err(Error::Corrupt("invalid code length"));
+        }
+        if lengths[entry.symbol as usize] != 0 {
+            return Err(Error::Corrupt("symbol has more than one code"));
+        }
+        lengths[entry.symbol as usize] = entry.length;
+    }
+
+    let kraft: u64 = entries
+        .iter()
+        .map(|entry| 1u64 << (MAX_CODE_LENGTH - entry.length))
+        .sum();
+    if kraft > 1 << MAX_CODE_LENGTH {
+        return Err(Error::Corrupt("code lengths are over-subscribed"));
+    }
+
+    let mut expected = lengths_to_entries(&lengths);
+    let mut actual = entries.to_vec();
+    expected.sort_by_key(|entry| entry.symbol);
+    actual.sort_by_key(|entry| entry.symbol);
+    if expected != actual {
+        return Err(Error::Corrupt(
+            "code is not the canonical one for its length",
+        ));
+    }
+
+    Ok(lengths)
+}
+
+pub mod dic {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        dic: &HaffmanCompressedDict,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        dic_to_entries(dic).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<HaffmanCompressedDict, D::Error> {
+        let entries = Vec::<CodeEntry>::deserialize(deserializer)?;
+        dic_from_entries(&entries).map_err(D::Error::custom)
+    }
+}
+
+pub mod code_lengths {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        lengths: &[u8],
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        lengths_to_entries(lengths).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Vec<u8>, D::Error> {
+        let entries = Vec::<CodeEntry>::deserialize(deserializer)?;
+        lengths_from_entries(&entries).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huffman_compress::{HuffmanTreeNode, count_frequency};
+
+    const SAMPLE: &[u8] = b"abcdefg\nabcdefg\nkkkkghfdklgh\ndfgfdh";
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        #[serde(with = "dic")]
+        dic: HaffmanCompressedDict,
+        #[serde(with = "code_lengths")]
+        lengths: Vec<u8>,
+    }
+
+    #[test]
+    fn test_code_lengths_json() {
+        let mut lengths = vec![0u8; 256];
+        lengths[b'a' as usize] = 1;
+        lengths[b'b' as usize] = 2;
+        lengths[b'c' as usize] = 2;
+        let json = serde_json::to_string(&lengths_to_entries(&lengths)).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"symbol":97,"code":"0","length":1},{"symbol":98,"code":"10","length":2},{"symbol":99,"code":"11","length":2}]"#
+        );
+
+        let entries: Vec<CodeEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(lengths_from_entries(&entries).unwrap(), lengths);
+
+        // right lengths, but not the canonical codes
+        let mut swapped = entries.clone();
+        swapped[1].code = "11".to_string();
+        swapped[2].code = "10".to_string();
+        assert!(lengths_from_entries(&swapped).is_err());
+        // three codes of one bit
+        let mut crowded = entries;
+        crowded[1].length = 1;
+        crowded[2].length = 1;
+        assert!(lengths_from_entries(&crowded).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frequency = count_frequency(SAMPLE);
+        let config = Config {
+            dic: huffman_compress::generate_haffman_dic_from_frequency(&frequency),
+            lengths: huffman_compress::build_code_lengths(&frequency),
+        };
+        let json = serde_json::to_string_pretty(&config).unwrap();
+        assert_eq!(serde_json::from_str::<Config>(&json).unwrap(), config);
+
+        let nodes = huffman_compress::generate_haffman_tree_nodes_with_frequency(&frequency);
+        let tree = huffman_compress::generate_haffman_tree(nodes)
+            .pop()
+            .unwrap();
+        let json = serde_json::to_string(&tree).unwrap();
+        assert!(serde_json::from_str::<HuffmanTreeNode>(&json).unwrap() == tree);
+    }
+
+    #[test]
+    fn test_rejects_bad_dictionaries() {
+        let entry = |symbol: u8, code: &str| CodeEntry {
+            symbol,
+            code: code.to_string(),
+            length: code.len() as u8,
+        };
+        assert!(dic_from_entries(&[entry(1, "0"), entry(2, "10")]).is_ok());
+        assert!(dic_from_entries(&[entry(1, "0"), entry(2, "01")]).is_err());
+        assert!(dic_from_entries(&[entry(1, "0"), entry(1, "10")]).is_err());
+        assert!(dic_from_entries(&[entry(1, "02")]).is_err());
+        assert!(dic_from_entries(&[entry(1, "")]).is_err());
+        let mut long = entry(1, "01");
+        long.length = 3;
+        assert!(dic_from_entries(&[long]).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::bitio::{BitOrder, BitReader, BitWriter};
+use crate::error::{Error, Result};
+use crate::huffman_compress::{self, CanonicalDecoder, take};
+
+// Token level coding for text: the input is split into words and the
+// separators between them, and every token that repeats gets a code of its
+// own. Everything else is spelled out after an escape code, one length byte
+// and then its bytes coded with a byte level table.
+//
+// magic            4 bytes, "HFT1"
+// flags            1 byte, huffman_compress::FLAG_STORED or 0
+// original length  u64, little endian
+// then, unless stored:
+// vocabulary size  u16, little endian
+// vocabulary       (token length: u8, token, code length: u8) * vocabulary size
+// escape           1 byte, code length of the escape, 0 when unused
+// literal table    huffman_compress::write_code_lengths of the escaped bytes
+// payload          codes, msb first, zero padded to a byte
+//
+// Codes are canonical over the vocabulary index, the escape coming last.
+
+const TOKEN_MAGIC: &[u8; 4] = b"HFT1";
+const TOKEN_HEADER_LEN: usize = 13;
+
+// longer runs are cut so a token length always fits in a byte
+pub const MAX_TOKEN_LEN: usize = 255;
+
+pub const MAX_VOCABULARY: usize = 4096;
+
+// tokens seen fewer times than this are escaped
+pub const MIN_TOKEN_COUNT: u64 = 2;
+
+// letters, digits, '_' and anything outside ascii, so utf-8 stays in one word
+fn is_word_byte(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || ch == b'_' || ch >= 0x80
+}
+
+// Maximal runs of word bytes and of separator bytes, in order, so joining the
+// tokens gives back `content`.
+pub fn tokenize(content: &[u8]) -> Vec<&[u8]> {
+    let mut res: Vec<&[u8]> = Vec::new();
+
+    let mut start = 0;
+    while start < content.len() {
+        let word = is_word_byte(content[start]);
+        let end = content[start..]
+            .iter()
+            .take(MAX_TOKEN_LEN)
+            .position(|&ch| is_word_byte(ch) != word)
+            .map_or((start + MAX_TOKEN_LEN).min(content.len()), |len| {
+                start + len
+            });
+        res.push(&content[start..end]);
+        start = end;
+    }
+
+    res
+}
+
+// The repeated tokens that cover the most bytes, at most MAX_VOCABULARY of
+// them. The order is only there to make the output deterministic.
+fn choose_vocabulary<'a>(tokens: &[&'a [u8]]) -> Vec<&'a [u8]> {
+    let mut counts: HashMap<&[u8], u64> = HashMap::new();
+    for &token in tokens {
+        *counts.entry(token).or_insert(0) += 1;
+    }
+
+    let mut vocabulary: Vec<(&[u8], u64)> = counts
+        .into_iter()
+        .filter(|&(_, count)| count >= MIN_TOKEN_COUNT)
+        .collect();
+    vocabulary.sort_by_key(|&(token, count)| (Reverse(count * token.len() as u64), token));
+    vocabulary.truncate(MAX_VOCABULARY);
+
+    vocabulary.into_iter().map(|(token, _)| token).collect()
+}
+
+pub fn compress_tokens(content: &[u8]) -> Vec<u8> {
+    let tokens = tokenize(content);
+    let vocabulary = choose_vocabulary(&tokens);
+    let index: HashMap<&[u8], usize> = vocabulary
+        .iter()
+        .enumerate()
+        .map(|(i, &token)| (token, i))
+        .collect();
+    let escape = vocabulary.len();
+
+    let mut frequency = vec![0u64; escape + 1];
+    let mut literal_frequency = vec![0u64; 256];
+    for &token in &tokens {
+        match index.get(token) {
+            Some(&i) => frequency[i] += 1,
+            None => {
+                frequency[escape] += 1;
+                for &ch in token {
+                    literal_frequency[ch as usize] += 1;
+                }
+            }
+        }
+    }
+
+    let lengths = huffman_compress::build_multibyte_code_lengths(&vocabulary, &frequency);
+    let codes = huffman_compress::canonical_codes(&lengths);
+    let literal_lengths = huffman_compress::build_code_lengths(&literal_frequency);
+    let literal_table = huffman_compress::packed_table_from_lengths(&literal_lengths);
+
+    let mut res: Vec<u8> = Vec::new();
+    res.extend_from_slice(TOKEN_MAGIC);
+    res.push(0);
+    res.extend_from_slice(&(content.len() as u64).to_le_bytes());
+
+    res.extend_from_slice(&(vocabulary.len() as u16).to_le_bytes());
+    for (i, token) in vocabulary.iter().enumerate() {
+        res.push(token.len() as u8);
+        res.extend_from_slice(token);
+        res.push(lengths[i]);
+    }
+    res.push(lengths[escape]);
+    huffman_compress::write_code_lengths(&mut res, &literal_lengths);
+
+    let mut writer = BitWriter::new(res, BitOrder::MsbFirst);
+    for &token in &tokens {
+        let symbol = index.get(token).copied().unwrap_or(escape);
+        writer
+            .write_bits(codes[symbol] as u64, lengths[symbol] as u32)
+            .expect("writing to a Vec cannot fail");
+        if symbol != escape {
+            continue;
+        }
+
+        writer
+            .write_bits(token.len() as u64, 8)
+            .expect("writing to a Vec cannot fail");
+        for &ch in token {
+            let (code, len) = literal_table[ch as usize];
+            writer
+                .write_bits(code as u64, len as u32)
+                .expect("writing to a Vec cannot fail");
+        }
+    }
+    let mut res = writer.finish().expect("writing to a Vec cannot fail");
+
+    // the vocabulary has to pay for itself
+    if res.len() >= TOKEN_HEADER_LEN + content.len() {
+        res.truncate(TOKEN_HEADER_LEN);
+        res[4] = huffman_compress::FLAG_STORED;
+        res.extend_from_slice(content);
+    }
+
+    res
+}
+
+pub fn decompress_tokens(data: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    if take(data, &mut pos, 4)? != TOKEN_MAGIC {
+        return Err(Error::Corrupt("not a token container"));
+    }
+    let flags = take(data, &mut pos, 1)?[0];
+    let original_len = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+
+    match flags {
+        0 => {}
+        huffman_compress::FLAG_STORED => {
+            if data.len() - pos != original_len as usize {
+                return Err(Error::Corrupt("stored content has the wrong length"));
+            }
+            return Ok(data[pos..].to_vec());
+        }
+        _ => return Err(Error::Corrupt("unknown token container flags")),
+    }
+
+    let vocabulary_size = u16::from_le_bytes(take(data, &mut pos, 2)?.try_into().unwrap());
+    let mut vocabulary: Vec<&[u8]> = Vec::with_capacity(vocabulary_size as usize);
+    let mut lengths: Vec<u8> = Vec::with_capacity(vocabulary_size as usize + 1);
+    for _ in 0..vocabulary_size {
+        let token_len = take(data, &mut pos, 1)?[0];
+        if token_len == 0 {
+            return Err(Error::Corrupt("empty token in vocabulary"));
+        }
+        vocabulary.push(take(data, &mut pos, token_len as usize)?);
+        lengths.push(take(data, &mut pos, 1)?[0]);
+    }
+    lengths.push(take(data, &mut pos, 1)?[0]);
+    if lengths
+        .iter()
+        .any(|&l| l > huffman_compress::MAX_CODE_LENGTH)
+    {
+        return Err(Error::Corrupt("invalid code length"));
+    }
+    let literal_lengths = huffman_compress::read_code_lengths(data, &mut pos)?;
+
+    // canonical position -> vocabulary index, the escape being the last one
+    let ranks = huffman_compress::canonical_ranks(&lengths);
+    let decoder = CanonicalDecoder::from_counts(huffman_compress::length_counts(&lengths), vec![]);
+    let literal_decoder = CanonicalDecoder::from_lengths(&literal_lengths);
+
+    let payload = &data[pos..];
+    let mut reader = BitReader::new(payload, BitOrder::MsbFirst);
+    let mut res: Vec<u8> = Vec::new();
+    while (res.len() as u64) < original_len {
+        let Ok(Some(rank)) = decoder.read_index(&mut reader) else {
+            return Err(Error::Corrupt("token payload is truncated"));
+        };
+
+        match vocabulary.get(ranks[rank]) {
+            Some(token) => res.extend_from_slice(token),
+            None => {
+                let Ok(token_len) = reader.read_bits(8) else {
+                    return Err(Error::Corrupt("token payload is truncated"));
+                };
+                for _ in 0..token_len {
+                    let Ok(Some(ch)) = literal_decoder.read_symbol(&mut reader) else {
+                        return Err(Error::Corrupt("token payload is truncated"));
+                    };
+                    res.push(ch);
+                }
+            }
+        }
+    }
+
+    if res.len() as u64 != original_len {
+        return Err(Error::Corrupt("token runs past the original length"));
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_lines(lines: usize) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        for i in 0..lines {
+            let level = ["INFO", "WARN", "DEBUG"][i % 3];
+            res.extend_from_slice(
+                format!(
+                    "{} request {} handled by worker-{} in {} ms\n",
+                    level,
+                    i * 7919 % 100_000,
+                    i % 4,
+                    i % 37
+                )
+                .as_bytes(),
+            );
+        }
+        res
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("hello, wörld_1\n\n".as_bytes());
+        assert_eq!(
+            tokens,
+            vec![b"hello".as_slice(), b", ", "wörld_1".as_bytes(), b"\n\n"]
+        );
+
+        let long = vec![b'a'; MAX_TOKEN_LEN * 2 + 1];
+        let tokens = tokenize(&long);
+        assert_eq!(
+            tokens.iter().map(|t| t.len()).collect::<Vec<usize>>(),
+            vec![MAX_TOKEN_LEN, MAX_TOKEN_LEN, 1]
+        );
+        assert!(tokenize(b"").is_empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let log = log_lines(500);
+        let long_word = vec![b'x'; 1000];
+        for content in [
+            b"".as_slice(),
+            b"a",
+            b"the cat and the hat and the bat",
+            long_word.as_slice(),
+            log.as_slice(),
+        ] {
+            let compressed = compress_tokens(content);
+            assert_eq!(decompress_tokens(&compressed).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_beats_byte_coding_on_text() {
+        let log = log_lines(2000);
+        let compressed = compress_tokens(&log);
+        assert_eq!(compressed[4], 0);
+        assert!(compressed.len() < huffman_compress::compress(&log).len());
+        assert_eq!(decompress_tokens(&compressed).unwrap(), log);
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(decompress_tokens(b"HFC1").is_err());
+
+        let log = log_lines(100);
+        let compressed = compress_tokens(&log);
+        assert!(decompress_tokens(&compressed[..compressed.len() / 2]).is_err());
+    }
+}
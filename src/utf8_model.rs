@@ -0,0 +1,317 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::bitio::{BitOrder, BitReader, BitWriter};
+use crate::error::{Error, Result};
+use crate::huffman_compress::{self, CanonicalDecoder, take};
+
+// Coding over unicode scalar values instead of bytes, so a character that
+// takes three bytes in utf-8 is still one symbol. Bytes that are not part of
+// valid utf-8, and characters that did not make it into the alphabet, go out
+// as an escape code followed by the raw byte, which keeps the round trip
+// exact for any input.
+//
+// magic            4 bytes, "HFU1"
+// flags            1 byte, huffman_compress::FLAG_STORED or 0
+// original length  u64, little endian
+// then, unless stored:
+// alphabet size    u32, little endian
+// alphabet         (scalar delta: leb128, code length: u8) * alphabet size,
+//                  scalars ascending, each one relative to the previous
+// escape           1 byte, code length of the escape, 0 when unused
+// payload          codes, msb first, zero padded to a byte
+//
+// Codes are canonical over the alphabet index, the escape coming last.
+
+const UTF8_MAGIC: &[u8; 4] = b"HFU1";
+const UTF8_HEADER_LEN: usize = 13;
+
+// keeps every per-length count of the decoder within a u16
+pub const MAX_ALPHABET: usize = u16::MAX as usize - 1;
+
+// A valid character, or a byte that is not part of one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Utf8Symbol {
+    Char(char),
+    Invalid(u8),
+}
+
+pub fn utf8_symbols(content: &[u8]) -> Vec<Utf8Symbol> {
+    let mut res: Vec<Utf8Symbol> = Vec::with_capacity(content.len());
+    for chunk in content.utf8_chunks() {
+        res.extend(chunk.valid().chars().map(Utf8Symbol::Char));
+        res.extend(chunk.invalid().iter().map(|&b| Utf8Symbol::Invalid(b)));
+    }
+
+    res
+}
+
+// the most frequent characters, ascending
+fn choose_alphabet(counts: &HashMap<char, u64>) -> Vec<char> {
+    let mut alphabet: Vec<(char, u64)> = counts.iter().map(|(&ch, &count)| (ch, count)).collect();
+    if alphabet.len() > MAX_ALPHABET {
+        alphabet.sort_by_key(|&(ch, count)| (Reverse(count), ch));
+        alphabet.truncate(MAX_ALPHABET);
+    }
+
+    let mut alphabet: Vec<char> = alphabet.into_iter().map(|(ch, _)| ch).collect();
+    alphabet.sort_unstable();
+
+    alphabet
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_leb128(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut value: u32 = 0;
+    for shift in (0..32).step_by(7) {
+        let byte = take(data, pos, 1)?[0];
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::Corrupt("leb128 value is too long"))
+}
+
+pub fn compress_utf8(content: &[u8]) -> Vec<u8> {
+    let symbols = utf8_symbols(content);
+
+    let mut counts: HashMap<char, u64> = HashMap::new();
+    for &symbol in &symbols {
+        if let Utf8Symbol::Char(ch) = symbol {
+            *counts.entry(ch).or_insert(0) += 1;
+        }
+    }
+    let alphabet = choose_alphabet(&counts);
+    let index: HashMap<char, usize> = alphabet
+        .iter()
+        .enumerate()
+        .map(|(i, &ch)| (ch, i))
+        .collect();
+    let escape = alphabet.len();
+
+    let mut frequency = vec![0u64; escape + 1];
+    for (i, &ch) in alphabet.iter().enumerate() {
+        frequency[i] = counts[&ch];
+    }
+    frequency[escape] = content.len() as u64
+        - alphabet
+            .iter()
+            .map(|&ch| counts[&ch] * ch.len_utf8() as u64)
+            .sum::<u64>();
+
+    let spelled: Vec<String> = alphabet.iter().map(|ch| ch.to_string()).collect();
+    let lengths = huffman_compress::build_multibyte_code_lengths(&spelled, &frequency);
+    let codes = huffman_compress::canonical_codes(&lengths);
+
+    let mut res: Vec<u8> = Vec::new();
+    res.extend_from_slice(UTF8_MAGIC);
+    res.push(0);
+    res.extend_from_slice(&(content.len() as u64).to_le_bytes());
+
+    res.extend_from_slice(&(alphabet.len() as u32).to_le_bytes());
+    let mut prev = 0u32;
+    for (i, &ch) in alphabet.iter().enumerate() {
+        write_leb128(&mut res, ch as u32 - prev);
+        res.push(lengths[i]);
+        prev = ch as u32;
+    }
+    res.push(lengths[escape]);
+
+    let mut writer = BitWriter::new(res, BitOrder::MsbFirst);
+    let write_escaped = |writer: &mut BitWriter<Vec<u8>>, byte: u8| {
+        writer
+            .write_bits(codes[escape] as u64, lengths[escape] as u32)
+            .and_then(|_| writer.write_bits(byte as u64, 8))
+            .expect("writing to a Vec cannot fail");
+    };
+    for &symbol in &symbols {
+        match symbol {
+            Utf8Symbol::Char(ch) => match index.get(&ch) {
+                Some(&i) => writer
+                    .write_bits(codes[i] as u64, lengths[i] as u32)
+                    .expect("writing to a Vec cannot fail"),
+                None => {
+                    for &b in ch.encode_utf8(&mut [0u8; 4]).as_bytes() {
+                        write_escaped(&mut writer, b);
+                    }
+                }
+            },
+            Utf8Symbol::Invalid(b) => write_escaped(&mut writer, b),
+        }
+    }
+    let mut res = writer.finish().expect("writing to a Vec cannot fail");
+
+    // the alphabet has to pay for itself
+    if res.len() >= UTF8_HEADER_LEN + content.len() {
+        res.truncate(UTF8_HEADER_LEN);
+        res[4] = huffman_compress::FLAG_STORED;
+        res.extend_from_slice(content);
+    }
+
+    res
+}
+
+pub fn decompress_utf8(data: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    if take(data, &mut pos, 4)? != UTF8_MAGIC {
+        return Err(Error::Corrupt("not a utf-8 container"));
+    }
+    let flags = take(data, &mut pos, 1)?[0];
+    let original_len = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+
+    match flags {
+        0 => {}
+        huffman_compress::FLAG_STORED => {
+            if data.len() - pos != original_len as usize {
+                return Err(Error::Corrupt("stored content has the wrong length"));
+            }
+            return Ok(data[pos..].to_vec());
+        }
+        _ => return Err(Error::Corrupt("unknown utf-8 container flags")),
+    }
+
+    let alphabet_size = u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap());
+    if alphabet_size as usize > MAX_ALPHABET {
+        return Err(Error::Corrupt("utf-8 alphabet is too large"));
+    }
+    let mut alphabet: Vec<char> = Vec::with_capacity(alphabet_size as usize);
+    let mut lengths: Vec<u8> = Vec::with_capacity(alphabet_size as usize + 1);
+    let mut prev = 0u32;
+    for i in 0..alphabet_size {
+        let delta = read_leb128(data, &mut pos)?;
+        let scalar = prev.checked_add(delta).filter(|_| i == 0 || delta > 0);
+        let Some(ch) = scalar.and_then(char::from_u32) else {
+            return Err(Error::Corrupt("invalid character in utf-8 alphabet"));
+        };
+        alphabet.push(ch);
+        lengths.push(take(data, &mut pos, 1)?[0]);
+        prev = ch as u32;
+    }
+    lengths.push(take(data, &mut pos, 1)?[0]);
+    if lengths
+        .iter()
+        .any(|&l| l > huffman_compress::MAX_CODE_LENGTH)
+    {
+        return Err(Error::Corrupt("invalid code length"));
+    }
+
+    // canonical position -> alphabet index, the escape being the last one
+    let ranks = huffman_compress::canonical_ranks(&lengths);
+    let decoder = CanonicalDecoder::from_counts(huffman_compress::length_counts(&lengths), vec![]);
+
+    let payload = &data[pos..];
+    let mut reader = BitReader::new(payload, BitOrder::MsbFirst);
+    let mut res: Vec<u8> = Vec::new();
+    while (res.len() as u64) < original_len {
+        let Ok(Some(rank)) = decoder.read_index(&mut reader) else {
+            return Err(Error::Corrupt("utf-8 payload is truncated"));
+        };
+
+        match alphabet.get(ranks[rank]) {
+            Some(ch) => res.extend_from_slice(ch.encode_utf8(&mut [0u8; 4]).as_bytes()),
+            None => {
+                let Ok(byte) = reader.read_bits(8) else {
+                    return Err(Error::Corrupt("utf-8 payload is truncated"));
+                };
+                res.push(byte as u8);
+            }
+        }
+    }
+
+    if res.len() as u64 != original_len {
+        return Err(Error::Corrupt("character runs past the original length"));
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cjk_text(lines: usize) -> Vec<u8> {
+        let words = ["数据", "压缩", "编码", "霍夫曼", "字符", "文本", "测试"];
+        let mut res = String::new();
+        for i in 0..lines {
+            for j in 0..8 {
+                res.push_str(words[(i * 3 + j * j) % words.len()]);
+            }
+            res.push_str("。\n");
+        }
+        res.into_bytes()
+    }
+
+    #[test]
+    fn test_utf8_symbols() {
+        let symbols = utf8_symbols(b"a\xC3\xA9\xFF\xE6\x95");
+        assert_eq!(
+            symbols,
+            vec![
+                Utf8Symbol::Char('a'),
+                Utf8Symbol::Char('é'),
+                Utf8Symbol::Invalid(0xFF),
+                Utf8Symbol::Invalid(0xE6),
+                Utf8Symbol::Invalid(0x95),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_leb128() {
+        for value in [0, 1, 0x7F, 0x80, 0x3FFF, 0x10FFFF, u32::MAX] {
+            let mut out: Vec<u8> = Vec::new();
+            write_leb128(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_leb128(&out, &mut pos).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cjk = cjk_text(50);
+        let mut broken = cjk.clone();
+        // a lone continuation byte, an overlong encoding and a cut character
+        broken.splice(10..10, [0x80, 0xC0, 0xAF]);
+        broken.extend_from_slice(&cjk[..100]);
+        broken.extend_from_slice(&"霍".as_bytes()[..2]);
+
+        for content in [
+            b"".as_slice(),
+            b"a",
+            "héllo wörld".as_bytes(),
+            b"\xFF\xFE\xFD",
+            cjk.as_slice(),
+            broken.as_slice(),
+        ] {
+            let compressed = compress_utf8(content);
+            assert_eq!(decompress_utf8(&compressed).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_beats_byte_coding_on_cjk() {
+        let cjk = cjk_text(500);
+        let compressed = compress_utf8(&cjk);
+        assert_eq!(compressed[4], 0);
+        assert!(compressed.len() < huffman_compress::compress(&cjk).len());
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(decompress_utf8(b"HFU1").is_err());
+
+        let cjk = cjk_text(50);
+        let compressed = compress_utf8(&cjk);
+        assert!(decompress_utf8(&compressed[..compressed.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+use std::process::Command;
+
+// a target without std, only checked when its core library is installed
+const BARE_METAL_TARGET: &str = "thumbv7em-none-eabihf";
+
+fn check(root: &Path, extra: &[&str]) {
+    let output = Command::new(env!("CARGO"))
+        .args(["check", "--lib", "--offline", "--no-default-features"])
+        .args(extra)
+        .arg("--target-dir")
+        .arg(root.join("target/no-std-test"))
+        .current_dir(root)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        extra,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn bare_metal_installed() -> bool {
+    let Ok(output) = Command::new("rustc").args(["--print", "sysroot"]).output() else {
+        return false;
+    };
+    let sysroot = String::from_utf8_lossy(&output.stdout);
+    Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(BARE_METAL_TARGET)
+        .exists()
+}
+
+// The huffman core without the default "std" feature, on its own and with
+// serde. Checked into a target directory of its own, so the cargo running
+// this test is not waited on.
+#[test]
+fn test_no_std_build() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    check(root, &[]);
+    check(root, &["--features", "serde"]);
+
+    if bare_metal_installed() {
+        check(root, &["--target", BARE_METAL_TARGET]);
+    }
+}